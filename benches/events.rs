@@ -0,0 +1,63 @@
+// Renders synthetic songs with a note on every tick of every track, so that
+// looking up the next event is a big part of the work. With per-track cursors
+// the time per tick should stay flat as songs get longer.
+//
+// Run with `cargo bench --bench events`.
+
+use organism::org::{Display, Instrument, LoopRange, Note, Song, Timing, Track, Version};
+use organism::playback::Interpolation;
+use organism::{PlaybackEngine, SoundBank};
+
+use std::time::Instant;
+
+fn song(ticks: usize) -> Song {
+    let tracks = std::array::from_fn(|track| {
+        let notes = (0..ticks)
+            .map(|pos| Note {
+                pos: pos as i32,
+                key: if track < 8 { 24 + (pos % 48) as u8 } else { 36 },
+                len: 1,
+                vol: 200,
+                pan: 6
+            })
+            .collect();
+
+        Track {
+            inst: Instrument { freq: 1000, inst: 0, pipi: 0, notes: ticks as u16 },
+            notes
+        }
+    });
+
+    Song {
+        version: Version::Main,
+        display: Display::default(),
+        time: Timing { wait: 1, loop_range: LoopRange { start: 0, end: ticks as i32 } },
+        tracks,
+        trailing: Vec::new()
+    }
+}
+
+fn main() {
+    let bank = SoundBank::builtin().expect("the built-in sound bank loads");
+
+    println!("{:>8} {:>12} {:>12}", "ticks", "total", "per tick");
+
+    for &ticks in &[1_000, 5_000, 20_000, 60_000] {
+        let mut playback = PlaybackEngine::new(song(ticks), &bank).expect("the song is valid");
+
+        // Keep the mixing cheap so the event lookup shows
+        playback.loops = Some(0);
+        playback.interpolation = Interpolation::Nearest;
+        playback.set_sample_rate(8000);
+
+        let mut buf = vec![0; 4096];
+        let start = Instant::now();
+
+        while playback.render_to(&mut buf) == buf.len() {}
+
+        let elapsed = start.elapsed();
+
+        println!("{:>8} {:>10.2}ms {:>10.0}ns",
+            ticks, elapsed.as_secs_f64() * 1000.0, elapsed.as_nanos() as f64 / ticks as f64);
+    }
+}
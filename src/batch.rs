@@ -0,0 +1,242 @@
+use crate::cli::{Batch, Format};
+use crate::{engine, format_time, load, render_file};
+
+use organism::validate::Severity;
+use organism::{Error, Result, SoundBank};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Job {
+    input: PathBuf,
+    output: PathBuf
+}
+
+enum Outcome {
+    Rendered {
+        frames: u64,
+        sample_rate: u32,
+        elapsed: Duration
+    },
+    Invalid(usize),
+    Failed(Error)
+}
+
+fn is_pattern(name: &str) -> bool {
+    name.contains('*') || name.contains('?')
+}
+
+// * matches any run of characters, ? matches exactly one.
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None)         => true,
+        (Some('*'), _)       => matches(&pattern[1..], name) || (!name.is_empty() && matches(pattern, &name[1..])),
+        (Some('?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+        (Some(p), Some(n))   => p == n && matches(&pattern[1..], &name[1..]),
+        _                    => false
+    }
+}
+
+fn is_org(path: &Path) -> bool {
+    path.extension().is_some_and(|x| x.eq_ignore_ascii_case("org"))
+}
+
+fn sorted_dir(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|x| x.map(|x| x.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    entries.sort();
+
+    Ok(entries)
+}
+
+// Finds every song under dir, keeping the path relative to root.
+fn walk(root: &Path, dir: &Path, found: &mut Vec<(PathBuf, PathBuf)>) -> io::Result<()> {
+    for path in sorted_dir(dir)? {
+        if path.is_dir() {
+            walk(root, &path, found)?;
+        } else if is_org(&path) {
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            found.push((path, relative));
+        }
+    }
+
+    Ok(())
+}
+
+// Turns the command line inputs into (song, path relative to the output directory) pairs.
+fn expand(input: &str) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let path = Path::new(input);
+    let mut found = Vec::new();
+
+    let name = path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+
+    if is_pattern(name) {
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _                                 => Path::new(".")
+        };
+
+        let pattern = name.chars().collect::<Vec<_>>();
+
+        for path in sorted_dir(dir)? {
+            let file_name = path.file_name().and_then(|x| x.to_str()).unwrap_or("").to_string();
+
+            if path.is_file() && matches(&pattern, &file_name.chars().collect::<Vec<_>>()) {
+                found.push((path, PathBuf::from(file_name)));
+            }
+        }
+    } else if path.is_dir() {
+        walk(path, path, &mut found)?;
+    } else {
+        let file_name = path.file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file", input)))?;
+
+        found.push((path.to_path_buf(), PathBuf::from(file_name)));
+    }
+
+    if found.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no songs found for {}", input)));
+    }
+
+    Ok(found)
+}
+
+fn render_one(job: &Job, bank: &SoundBank, opts: &Batch) -> Outcome {
+    let start = Instant::now();
+
+    let result = (|| -> Result<Outcome> {
+        let org = load(&job.input)?;
+        let issues = org.validate(bank);
+        let errors = issues.iter().filter(|x| x.severity == Severity::Error).count();
+
+        if errors > 0 {
+            return Ok(Outcome::Invalid(errors));
+        }
+
+        let mut playback = engine(org, bank, &opts.settings)?;
+
+        if let Some(dir) = job.output.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let frames = render_file(&mut playback, &job.output, opts.settings.format, true)?;
+
+        Ok(Outcome::Rendered {
+            frames,
+            sample_rate: playback.get_output_format().sample_rate,
+            elapsed: start.elapsed()
+        })
+    })();
+
+    result.unwrap_or_else(Outcome::Failed)
+}
+
+pub fn run(opts: Batch) -> Result<()> {
+    let bank = SoundBank::builtin()?;
+
+    let extension = match opts.settings.format {
+        Format::Wav => "wav",
+        Format::Raw => "raw"
+    };
+
+    let mut jobs = Vec::new();
+
+    for input in opts.inputs.iter() {
+        for (path, relative) in expand(input)? {
+            jobs.push(Job { input: path, output: opts.output.join(relative).with_extension(extension) });
+        }
+    }
+
+    // Two inputs can flatten to the same name, and the second one would quietly overwrite the first
+    let mut outputs = HashMap::new();
+
+    for job in jobs.iter() {
+        if let Some(other) = outputs.insert(&job.output, &job.input) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{} and {} would both be rendered to {}", other.display(), job.input.display(), job.output.display())).into());
+        }
+    }
+
+    let start = Instant::now();
+    let next = AtomicUsize::new(0);
+    let mut outcomes = (0..jobs.len()).map(|_| None).collect::<Vec<Option<Outcome>>>();
+
+    thread::scope(|s| {
+        let (tx, rx) = mpsc::channel();
+
+        for _ in 0..opts.jobs.min(jobs.len()) {
+            let tx = tx.clone();
+            let (jobs, next, bank, opts) = (&jobs, &next, &bank, &opts);
+
+            s.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+
+                if i >= jobs.len() {
+                    break;
+                }
+
+                // The receiver only goes away once everything's done
+                let _ = tx.send((i, render_one(&jobs[i], bank, opts)));
+            });
+        }
+
+        drop(tx);
+
+        for (done, (i, outcome)) in rx.iter().enumerate() {
+            if !opts.quiet {
+                eprint!("\rRendered {}/{} songs", done + 1, jobs.len());
+            }
+
+            outcomes[i] = Some(outcome);
+        }
+
+        if !opts.quiet {
+            eprintln!();
+        }
+    });
+
+    let (mut rendered, mut invalid, mut failed) = (0, 0, 0);
+    let mut audio = 0.0;
+
+    for (job, outcome) in jobs.iter().zip(outcomes) {
+        match outcome.expect("every job reports back") {
+            Outcome::Rendered { frames, sample_rate, elapsed } => {
+                rendered += 1;
+                audio += frames as f64 / sample_rate as f64;
+
+                println!("ok       {} -> {} ({} in {:.2}s)",
+                    job.input.display(), job.output.display(), format_time(frames, sample_rate), elapsed.as_secs_f64());
+            }
+            Outcome::Invalid(errors) => {
+                invalid += 1;
+
+                println!("invalid  {}: {} error(s), run validate for details", job.input.display(), errors);
+            }
+            Outcome::Failed(e) => {
+                failed += 1;
+
+                println!("failed   {}: {}", job.input.display(), e);
+            }
+        }
+    }
+
+    let audio = audio as u32;
+
+    println!("Rendered {} of {} songs ({} invalid, {} failed), {:02}:{:02}:{:02} of audio in {:.2}s",
+        rendered, jobs.len(), invalid, failed,
+        audio / 3600, audio / 60 % 60, audio % 60, start.elapsed().as_secs_f64());
+
+    if rendered < jobs.len() {
+        return Err(io::Error::other(format!("{} of {} songs could not be rendered", jobs.len() - rendered, jobs.len())).into());
+    }
+
+    Ok(())
+}
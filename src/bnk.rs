@@ -1,3 +1,5 @@
+use crate::error::{Error, Result};
+use crate::reader::Reader;
 use crate::wav;
 
 use std::io;
@@ -22,19 +24,33 @@ impl fmt::Display for SoundBank {
     }
 }
 
+// Org Maker's WAVE100 followed by its drum samples
+const BUILTIN: &[u8] = include_bytes!("../assets/samples/Samples.bnk");
+
 impl SoundBank {
-    pub fn load_from<R: io::Read>(mut f: R) -> io::Result<SoundBank> {
+    pub fn builtin() -> Result<SoundBank> {
+        SoundBank::load_from(BUILTIN)
+    }
+
+    pub fn load_from<R: io::Read>(f: R) -> Result<SoundBank> {
+        let mut f = Reader::new(f);
+
         // no box [0; 25600] yet
         let mut wave100 = vec![0; 25600].into_boxed_slice();
         
-        f.read_exact(&mut *wave100)?;
+        f.bytes(&mut wave100)?;
         
         let mut samples = Vec::with_capacity(16);
         
         loop {
-            match wav::WavSample::read_from(&mut f) {
+            let offset = f.offset();
+
+            match wav::WavSample::read(&mut f) {
                 Ok(sample) => samples.push(sample),
-                Err(_)     => return Ok(SoundBank { wave100, samples })
+                // Clean end of file between two samples
+                Err(Error::Truncated { .. }) if f.offset() == offset =>
+                    return Ok(SoundBank { wave100, samples }),
+                Err(e)     => return Err(e)
             }
         }
     }
@@ -0,0 +1,404 @@
+use organism::org::Version;
+use organism::playback::{Fade, FadeCurve, Interpolation, Mixing, TickTiming};
+use organism::wav::SampleFormat;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub const USAGE: &str = "\
+Usage:
+    organism render <file> [options]    Render a song to WAV or raw PCM
+    organism info <file>                Show information about a song
+    organism validate <file>...         Check songs for errors
+    organism stems <file> --output <dir> [options]
+                                        Render every track to its own file
+    organism export <file> --output <path> [--format org-02|org-03]
+                                        Write a song back out as Organya
+    organism batch <input>... --output <dir> [options]
+                                        Render many songs at once
+
+Render options (also apply to stems and batch):
+    --loops <n>             Times to repeat the loop after the first pass, or forever (default: 1)
+    --tail-seconds <n>      Seconds to keep playing after the last loop (default: 0)
+    --fade-seconds <n>      Fade out over this many seconds after the last loop, stretching the tail if needed
+    --fade-curve <curve>    linear, exponential or equal-power (default: linear)
+    --format <wav|raw>      Output format (default: wav)
+    --sample-rate <hz>      Output sample rate (default: 44100)
+    --sample-format <fmt>   s16, s24 or f32 (default: s16)
+    --interpolation <mode>  nearest, linear, cosine, cubic, lanczos4 or lanczos6 (default: lanczos6)
+    --mixing <mode>         float, or saturating to clip after every source like older versions (default: float)
+    --timing <mode>         exact, truncated to round every tick down like older versions,
+                            or timer to follow the uneven timing of Windows (default: exact)
+    --seed <n>              Seed for --timing timer's jitter, the same seed gives the same render (default: 0)
+    --threads <n>           Threads to mix each song on; the output is the same either way (default: 1)
+    --mute <tracks>         Tracks to leave out, like 0,3 (0-7 are melody, 8-15 are drums)
+    --solo <tracks>         Only play these tracks, minus any that are muted
+    --output <path>         Write to a file instead of stdout (sizes are filled in afterwards)
+    --quiet                 Don't print progress or warnings
+
+Batch options:
+    --output <dir>          Where to put the rendered files, mirroring the input tree
+    --jobs <n>              Songs to render at once (default: number of cores)
+
+Batch inputs can be files, directories (searched for .org files) or
+patterns like songs/*.org, where only the file name may contain * and ?.
+";
+
+pub struct Usage(pub String);
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum Format {
+    Wav,
+    Raw
+}
+
+// Everything that affects how a song sounds
+#[derive(Clone)]
+pub struct Settings {
+    // None loops forever
+    pub loops: Option<usize>,
+    pub tail_seconds: u32,
+    pub fade: Option<Fade>,
+    pub format: Format,
+    pub sample_rate: u32,
+    pub sample_format: SampleFormat,
+    pub interpolation: Interpolation,
+    pub mixing: Mixing,
+    pub timing: TickTiming,
+    pub threads: usize,
+    pub muted: [bool; 16]
+}
+
+pub struct Render {
+    pub input: PathBuf,
+    pub output: Option<PathBuf>,
+    pub quiet: bool,
+    pub settings: Settings
+}
+
+pub struct Stems {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub quiet: bool,
+    pub settings: Settings
+}
+
+pub struct Batch {
+    pub inputs: Vec<String>,
+    pub output: PathBuf,
+    pub jobs: usize,
+    pub quiet: bool,
+    pub settings: Settings
+}
+
+pub struct Export {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub version: Option<Version>
+}
+
+pub enum Command {
+    Render(Render),
+    Info(PathBuf),
+    Validate(Vec<PathBuf>),
+    Stems(Stems),
+    Export(Export),
+    Batch(Batch),
+    Help
+}
+
+// Flags and positional arguments, before we know what they mean.
+struct Matches {
+    values: HashMap<&'static str, String>,
+    switches: Vec<&'static str>,
+    positional: Vec<String>
+}
+
+impl Matches {
+    fn parse(args: &[String], options: &[&'static str], switches: &[&'static str]) -> Result<Matches, Usage> {
+        let mut m = Matches { values: HashMap::new(), switches: Vec::new(), positional: Vec::new() };
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                m.positional.push(arg.clone());
+                continue;
+            }
+
+            let (name, inline) = match arg.find('=') {
+                Some(i) => (&arg[..i], Some(arg[i + 1..].to_string())),
+                None    => (&arg[..], None)
+            };
+
+            if let Some(&name) = options.iter().find(|&&x| x == name) {
+                // A flag where the value should be is most likely a typo.
+                // Values that really start with -- can go after an =.
+                let value = match inline {
+                    Some(v) => v,
+                    None    => args.next().filter(|x| !x.starts_with("--")).cloned()
+                        .ok_or_else(|| Usage(format!("{} needs a value", name)))?
+                };
+
+                m.values.insert(name, value);
+            } else if let Some(&name) = switches.iter().find(|&&x| x == name) {
+                if inline.is_some() {
+                    return Err(Usage(format!("{} doesn't take a value", name)));
+                }
+
+                m.switches.push(name);
+            } else {
+                return Err(Usage(format!("unknown option {}", name)));
+            }
+        }
+
+        Ok(m)
+    }
+
+    fn value<T: FromStr>(&self, name: &'static str, default: T) -> Result<T, Usage> {
+        match self.values.get(name) {
+            Some(v) => v.parse().map_err(|_| Usage(format!("invalid value for {}: {}", name, v))),
+            None    => Ok(default)
+        }
+    }
+
+    fn switch(&self, name: &'static str) -> bool {
+        self.switches.contains(&name)
+    }
+
+    fn one_input(&self) -> Result<PathBuf, Usage> {
+        match self.positional.len() {
+            0 => Err(Usage("no input file given".into())),
+            1 => Ok(PathBuf::from(&self.positional[0])),
+            _ => Err(Usage(format!("unexpected argument {}", self.positional[1])))
+        }
+    }
+}
+
+const RENDER_OPTIONS: &[&str] = &["--loops", "--tail-seconds", "--fade-seconds", "--fade-curve", "--format", "--sample-rate", "--sample-format", "--interpolation", "--mixing", "--timing", "--seed", "--threads", "--mute", "--solo", "--output"];
+
+// A comma separated list of track numbers
+fn tracks(m: &Matches, name: &'static str) -> Result<Option<Vec<usize>>, Usage> {
+    let list = match m.values.get(name) {
+        Some(list) => list,
+        None       => return Ok(None)
+    };
+
+    list.split(',')
+        .map(|x| match x.trim().parse() {
+            Ok(track) if track < 16 => Ok(track),
+            _                       => Err(Usage(format!("invalid track for {}: {}", name, x)))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn settings(m: &Matches) -> Result<Settings, Usage> {
+    let format = match m.values.get("--format").map(String::as_str) {
+        None | Some("wav") => Format::Wav,
+        Some("raw")        => Format::Raw,
+        Some(other)        => return Err(Usage(format!("unknown render format {}", other)))
+    };
+
+    let sample_rate = m.value("--sample-rate", 44100)?;
+
+    if !(8000..=192000).contains(&sample_rate) {
+        return Err(Usage(format!("sample rate {} is out of range (8000 to 192000)", sample_rate)));
+    }
+
+    let fade_seconds: f32 = m.value("--fade-seconds", 0.0)?;
+
+    if !(0.0..=3600.0).contains(&fade_seconds) {
+        return Err(Usage(format!("fade of {} seconds is out of range (0 to 3600)", fade_seconds)));
+    }
+
+    let tail_seconds = m.value("--tail-seconds", 0)?;
+
+    if tail_seconds > 3600 {
+        return Err(Usage(format!("tail of {} seconds is out of range (0 to 3600)", tail_seconds)));
+    }
+
+    let fade = Some(Fade { seconds: fade_seconds, curve: m.value("--fade-curve", FadeCurve::default())? })
+        .filter(|x| x.seconds > 0.0);
+
+    let mut timing = m.value("--timing", TickTiming::default())?;
+
+    match &mut timing {
+        TickTiming::Timer(model) => model.seed = m.value("--seed", 0)?,
+        _ if m.values.contains_key("--seed") => return Err(Usage("--seed only works with --timing timer".into())),
+        _ => {}
+    }
+
+    let threads = m.value("--threads", 1)?;
+
+    if threads == 0 {
+        return Err(Usage("--threads must be at least 1".into()));
+    }
+
+    let mut muted = [false; 16];
+
+    if let Some(solo) = tracks(m, "--solo")? {
+        for (i, mute) in muted.iter_mut().enumerate() {
+            *mute = !solo.contains(&i);
+        }
+    }
+
+    for track in tracks(m, "--mute")?.unwrap_or_default() {
+        muted[track] = true;
+    }
+
+    let loops = match m.values.get("--loops").map(String::as_str) {
+        Some("forever") => None,
+        _               => Some(m.value("--loops", 1)?)
+    };
+
+    Ok(Settings {
+        loops,
+        tail_seconds,
+        fade,
+        format,
+        sample_rate,
+        sample_format: m.value("--sample-format", SampleFormat::default())?,
+        interpolation: m.value("--interpolation", Interpolation::default())?,
+        mixing: m.value("--mixing", Mixing::default())?,
+        timing,
+        threads,
+        muted
+    })
+}
+
+pub fn parse(args: &[String]) -> Result<Command, Usage> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None                  => return Err(Usage("no command given".into()))
+    };
+
+    match command {
+        "render" => {
+            let m = Matches::parse(rest, RENDER_OPTIONS, &["--quiet"])?;
+
+            let render = Render {
+                input: m.one_input()?,
+                output: m.values.get("--output").map(PathBuf::from),
+                quiet: m.switch("--quiet"),
+                settings: settings(&m)?
+            };
+
+            if render.output.is_some() && render.settings.loops.is_none() {
+                return Err(Usage("--loops forever only works when writing to stdout".into()));
+            }
+
+            Ok(Command::Render(render))
+        }
+        "stems" => {
+            let m = Matches::parse(rest, RENDER_OPTIONS, &["--quiet"])?;
+
+            let output = m.values.get("--output").ok_or_else(|| Usage("stems needs --output".into()))?;
+
+            let settings = settings(&m)?;
+
+            if settings.loops.is_none() {
+                return Err(Usage("stems can't loop forever".into()));
+            }
+
+            Ok(Command::Stems(Stems {
+                input: m.one_input()?,
+                output: PathBuf::from(output),
+                quiet: m.switch("--quiet"),
+                settings
+            }))
+        }
+        "batch" => {
+            let m = Matches::parse(rest, &[RENDER_OPTIONS, &["--jobs"]].concat(), &["--quiet"])?;
+
+            if m.positional.is_empty() {
+                return Err(Usage("no inputs given".into()));
+            }
+
+            let output = m.values.get("--output").ok_or_else(|| Usage("batch needs --output".into()))?;
+            let cores = std::thread::available_parallelism().map_or(1, |x| x.get());
+            let jobs = m.value("--jobs", cores)?;
+
+            if jobs == 0 {
+                return Err(Usage("--jobs must be at least 1".into()));
+            }
+
+            let settings = settings(&m)?;
+
+            if settings.loops.is_none() {
+                return Err(Usage("batch can't loop forever".into()));
+            }
+
+            Ok(Command::Batch(Batch {
+                inputs: m.positional.clone(),
+                output: PathBuf::from(output),
+                jobs,
+                quiet: m.switch("--quiet"),
+                settings
+            }))
+        }
+        "info" => {
+            let m = Matches::parse(rest, &[], &[])?;
+
+            Ok(Command::Info(m.one_input()?))
+        }
+        "validate" => {
+            let m = Matches::parse(rest, &[], &[])?;
+
+            if m.positional.is_empty() {
+                return Err(Usage("no input files given".into()));
+            }
+
+            Ok(Command::Validate(m.positional.iter().map(PathBuf::from).collect()))
+        }
+        "export" => {
+            let m = Matches::parse(rest, &["--format", "--output"], &[])?;
+
+            let version = match m.values.get("--format").map(String::as_str) {
+                None           => None,
+                Some("org-02") => Some(Version::Main),
+                Some("org-03") => Some(Version::Extended),
+                Some(other)    => return Err(Usage(format!("unknown export format {}", other)))
+            };
+
+            let output = m.values.get("--output").ok_or_else(|| Usage("export needs --output".into()))?;
+
+            Ok(Command::Export(Export {
+                input: m.one_input()?,
+                output: PathBuf::from(output),
+                version
+            }))
+        }
+        "help" | "--help" | "-h" => Ok(Command::Help),
+        other => Err(Usage(format!("unknown command {}", other)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn flag_is_not_a_value() {
+        match Matches::parse(&args("song.org --output --quiet"), RENDER_OPTIONS, &["--quiet"]) {
+            Err(Usage(message)) => assert_eq!(message, "--output needs a value"),
+            Ok(_)               => panic!("--quiet was taken as the output")
+        }
+
+        let m = Matches::parse(&args("song.org --output=--quiet"), RENDER_OPTIONS, &["--quiet"]).ok().unwrap();
+
+        assert_eq!(m.values["--output"], "--quiet");
+        assert!(m.switches.is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+use crate::validate::{Issue, Severity};
+
+use std::error;
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // Wrong signature at the start of a file or chunk
+    BadMagic {
+        offset: u64,
+        expected: &'static str,
+        found: Vec<u8>
+    },
+    // Ran out of data while reading `expected` bytes at `offset`
+    Truncated {
+        offset: u64,
+        expected: usize
+    },
+    // Valid RIFF data that we just can't play
+    UnsupportedWaveFormat {
+        offset: u64,
+        reason: &'static str
+    },
+    // A track refers to a waveform or drum that isn't in the sound bank
+    InstrumentOutOfRange {
+        track: usize,
+        inst: u8
+    },
+    // Organya stores note counts as u16
+    TooManyNotes {
+        track: usize,
+        count: usize
+    },
+    // The song loaded, but failed validation
+    Invalid(Vec<Issue>)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::BadMagic { offset, expected, found } => {
+                write!(f, "expected {} at offset {:#x}, found \"", expected, offset)?;
+
+                for &b in found {
+                    write!(f, "{}", std::ascii::escape_default(b))?;
+                }
+
+                write!(f, "\"")
+            }
+            Error::Truncated { offset, expected } =>
+                write!(f, "unexpected end of file reading {} bytes at offset {:#x}", expected, offset),
+            Error::UnsupportedWaveFormat { offset, reason } =>
+                write!(f, "unsupported wave format at offset {:#x}: {}", offset, reason),
+            Error::InstrumentOutOfRange { track, inst } =>
+                write!(f, "track {} uses instrument {}, which is not in the sound bank", track, inst),
+            Error::TooManyNotes { track, count } =>
+                write!(f, "track {} has {} notes, but Organya can only store {}", track, count, u16::MAX),
+            Error::Invalid(issues) => {
+                let errors = issues.iter().filter(|x| x.severity == Severity::Error).count();
+
+                write!(f, "song failed validation with {} error(s)", errors)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _            => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
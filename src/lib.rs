@@ -0,0 +1,40 @@
+//! Organism renders [Organya] songs, the music format used by Cave Story, to PCM audio.
+//!
+//! ```no_run
+//! use organism::{PlaybackEngine, Song, SoundBank};
+//!
+//! # fn main() -> organism::Result<()> {
+//! let song = Song::load_from(std::fs::File::open("song.org")?)?;
+//! let bank = SoundBank::builtin()?;
+//!
+//! let mut engine = PlaybackEngine::new(song, &bank)?;
+//! let mut frames = vec![0; 4096];
+//!
+//! // Interleaved 16-bit stereo, left channel in the low half.
+//! // A short count means the last loop is over.
+//! while engine.render_to(&mut frames) == frames.len() {
+//!     // ...
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [Organya]: https://www.cavestory.org/download/music.php
+
+pub mod bnk;
+pub mod error;
+pub mod music;
+pub mod org;
+pub mod playback;
+pub mod player;
+mod reader;
+mod stuff;
+pub mod validate;
+pub mod wav;
+
+pub use crate::bnk::SoundBank;
+pub use crate::error::{Error, Result};
+pub use crate::music::MusicManager;
+pub use crate::org::Song;
+pub use crate::playback::PlaybackEngine;
+pub use crate::player::{Controller, Player};
@@ -1,139 +1,417 @@
-mod bnk;
-mod org;
-mod playback;
-mod stuff;
-mod wav;
+mod batch;
+mod cli;
 
-use crate::playback::PlaybackEngine;
+use crate::cli::{Command, Format, Settings, Usage};
+
+use organism::validate::Severity;
+use organism::wav::{self, WavFormat, WavWriter};
+use organism::{Error, PlaybackEngine, Result, Song, SoundBank};
 
 use std::env;
 use std::fs::File;
-use std::io::{self, BufReader, Write};
+use std::process;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
 use std::time::{Duration, Instant};
 
-use byteorder::{LE, WriteBytesExt};
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
 
-const BANK_DATA: &[u8] = include_bytes!("../assets/samples/Samples.bnk");
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(Usage(e)) => {
+            eprintln!("organism: {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    let result = match command {
+        Command::Render(opts)    => render(opts),
+        Command::Info(input)     => info(&input),
+        Command::Validate(files) => validate(&files),
+        Command::Stems(opts)     => stems(opts),
+        Command::Export(opts)    => export(opts),
+        Command::Batch(opts)     => batch::run(opts),
+        Command::Help            => {
+            print!("{}", cli::USAGE);
+            Ok(())
+        }
+    };
 
-fn main() -> io::Result<()> {
-    let args = env::args().skip(1).collect::<Vec<_>>();
-    let output_wav = args.get(2).map_or(false, |x| x == "wav");
-    let loops = args.get(1).map_or(1, |x| x.parse().unwrap_or(1));
-    let extra = args.get(3).map_or(0, |x| x.parse().unwrap_or(0));
+    if let Err(e) = result {
+        eprintln!("organism: {}", e);
+        process::exit(1);
+    }
+}
+
+fn load(path: &Path) -> Result<Song> {
+    let file = File::open(path)?;
+
+    Song::load_from(BufReader::new(file))
+}
+
+// Loads and validates a song, printing any issues. Fails if there were errors.
+fn load_valid(path: &Path, bank: &SoundBank, quiet: bool) -> Result<Song> {
+    let org = load(path)?;
+    let issues = org.validate(bank);
+
+    for issue in issues.iter() {
+        if !quiet || issue.severity == Severity::Error {
+            eprintln!("{}: {}", path.display(), issue);
+        }
+    }
+
+    if issues.iter().any(|x| x.severity == Severity::Error) {
+        return Err(Error::Invalid(issues));
+    }
+
+    Ok(org)
+}
+
+fn format_time(frames: u64, sample_rate: u32) -> String {
+    let secs = frames / sample_rate as u64;
+
+    format!("{:02}:{:02}", secs / 60, secs % 60)
+}
+
+fn engine(org: Song, bank: &SoundBank, settings: &Settings) -> Result<PlaybackEngine> {
+    let mut playback = PlaybackEngine::new(org, bank)?;
+    playback.loops = settings.loops;
+    playback.extra = settings.tail_seconds;
+    playback.fade = settings.fade;
+    playback.interpolation = settings.interpolation;
+    playback.mixing = settings.mixing;
+    playback.timing = settings.timing;
+    playback.threads = settings.threads;
+    playback.set_sample_rate(settings.sample_rate);
+    playback.set_sample_format(settings.sample_format);
+
+    for (track, &muted) in settings.muted.iter().enumerate() {
+        playback.set_track_muted(track, muted);
+    }
+
+    Ok(playback)
+}
+
+fn render(opts: cli::Render) -> Result<()> {
+    let bnk = SoundBank::builtin()?;
+    let org = load_valid(&opts.input, &bnk, opts.quiet)?;
+
+    let mut playback = engine(org, &bnk, &opts.settings)?;
+
+    match &opts.output {
+        Some(path) => { render_file(&mut playback, path, opts.settings.format, opts.quiet)?; }
+        None => {
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+
+            // Can't seek back on a pipe, so the header has to be right from the start.
+            if opts.settings.format == Format::Wav {
+                let format = playback.get_output_format();
+
+                // Looping forever gets the biggest size that fits, like other streaming tools do.
+                let data_size = match (playback.loops, playback.get_total_samples()) {
+                    (None, _)         => Some(u32::MAX / format.block_align() * format.block_align()),
+                    (_, Some(frames)) => frames.checked_mul(format.block_align()),
+                    (_, None)         => None
+                };
+
+                let data_size = data_size.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                    "too long for a wav file, try --format raw"))?;
+
+                wav::write_header(&mut out, format, data_size)?;
+            }
+
+            render_frames(&mut playback, &mut out, opts.quiet)?;
+            out.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+// A file we're rendering into
+enum Output {
+    Wav(WavWriter<BufWriter<File>>),
+    Raw(BufWriter<File>)
+}
+
+impl Output {
+    fn create(path: &Path, format: Format, wav_format: WavFormat) -> io::Result<Output> {
+        let file = BufWriter::new(File::create(path)?);
+
+        match format {
+            Format::Wav => Ok(Output::Wav(WavWriter::new(file, wav_format)?)),
+            Format::Raw => Ok(Output::Raw(file))
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Output::Wav(out)     => out.finish().map(drop),
+            Output::Raw(mut out) => out.flush()
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Wav(out) => out.write(buf),
+            Output::Raw(out) => out.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Wav(out) => out.flush(),
+            Output::Raw(out) => out.flush()
+        }
+    }
+}
+
+struct Progress {
+    quiet: bool,
+    sample_rate: u32,
+    total_time: String,
+    time: Duration,
+    start: Instant
+}
+
+impl Progress {
+    fn new(playback: &PlaybackEngine, quiet: bool) -> Progress {
+        let sample_rate = playback.get_output_format().sample_rate;
+
+        Progress {
+            quiet,
+            sample_rate,
+            total_time: playback.get_total_samples().map_or("--:--".into(), |x| format_time(x as u64, sample_rate)),
+            time: Duration::new(0, 0),
+            start: Instant::now()
+        }
+    }
+
+    fn update(&mut self, playback: &PlaybackEngine, frames: usize) {
+        self.time += Duration::from_secs_f64(frames as f64 / self.sample_rate as f64);
 
-    let file  = File::open(&args[0])?;
-    let f     = BufReader::new(file);
+        if self.quiet {
+            return;
+        }
 
-    let org = org::Song::load_from(f)?;
-    let bnk = bnk::SoundBank::load_from(BANK_DATA)?;
+        let time = self.time;
+        let speed = time.as_secs_f64() / self.start.elapsed().as_secs_f64();
 
-    let mut playback = PlaybackEngine::new(org, bnk);
-    playback.loops = loops;
-    playback.extra = extra;
-    let mut time = Duration::new(0, 0);
+        let (bar, beat, _) = playback.song().display.bar_beat_step(playback.get_position());
 
-    if output_wav {
-        print_wav_header(playback.get_total_samples())?;
+        eprint!("\rRendering {:02}:{:02}/{} (bar {:3}.{}) ({:5.2}x speed)",
+            time.as_secs() / 60, time.as_secs() % 60, self.total_time, bar + 1, beat + 1, speed);
     }
 
-    let total_frames = playback.get_total_samples();
-    let total_secs = total_frames / 44100;
-    let total_time = format!("{:02}:{:02}", total_secs / 60, total_secs % 60);
-    let mut frames_done = 0;
-    let now = Instant::now();
+    fn finish(self) {
+        if !self.quiet {
+            eprintln!();
+        }
+    }
+}
+
+// Returns how many frames were written.
+fn render_file(playback: &mut PlaybackEngine, path: &Path, format: Format, quiet: bool) -> io::Result<u64> {
+    let mut out = Output::create(path, format, playback.get_output_format())?;
+
+    let frames = render_frames(playback, &mut out, quiet)?;
+
+    out.finish()?;
+
+    Ok(frames)
+}
+
+// Frames to render at a time. Long enough that mixing threads get a good run at it.
+const BLOCK: usize = 4410;
+
+fn render_frames(playback: &mut PlaybackEngine, out: &mut dyn Write, quiet: bool) -> io::Result<u64> {
+    let mut progress = Progress::new(playback, quiet);
+
+    let block_align = playback.get_output_format().block_align() as usize;
+    let mut buf = vec![0x0; BLOCK * block_align];
+    let mut total = 0;
 
     loop {
-        let speed = time.as_secs_f64() / now.elapsed().as_secs_f64();
+        let frames = playback.render_bytes(&mut buf);
+        total += frames as u64;
+
+        out.write_all(&buf[..frames * block_align])?;
 
-        eprint!("\rRendering {:02}:{:02}/{} ({:5.2}x speed)",
-            time.as_secs() / 60, time.as_secs() % 60, total_time, speed);
+        progress.update(playback, frames);
 
-        let mut buf = vec![0x0; 441];
+        if frames < BLOCK {
+            break;
+        }
+    }
 
-        let frames = playback.render_to(&mut buf);
+    progress.finish();
 
-        for frame in &buf[..frames] {
-            io::stdout().write_all(&frame.to_le_bytes()).unwrap();
+    Ok(total)
+}
+
+fn stems(opts: cli::Stems) -> Result<()> {
+    let bnk = SoundBank::builtin()?;
+    let org = load_valid(&opts.input, &bnk, opts.quiet)?;
+
+    let name = opts.input.file_stem().map_or("song".into(), |x| x.to_string_lossy());
+    let extension = match opts.settings.format {
+        Format::Wav => "wav",
+        Format::Raw => "raw"
+    };
+
+    let mut playback = engine(org, &bnk, &opts.settings)?;
+    let format = playback.get_output_format();
+    let sample_format = playback.get_sample_format();
+
+    std::fs::create_dir_all(&opts.output)?;
+
+    // Empty and muted tracks would just be silence
+    let mut outputs = Vec::new();
+
+    for (i, track) in playback.song().tracks.iter().enumerate() {
+        if !track.notes.is_empty() && !playback.is_track_muted(i) {
+            let path = opts.output.join(format!("{}-{:02}.{}", name, i, extension));
+
+            outputs.push((i, Output::create(&path, opts.settings.format, format)?));
         }
+    }
 
-        time += Duration::from_secs_f64(frames as f64 / 44100.0);
+    let mut progress = Progress::new(&playback, opts.quiet);
+
+    let block_align = format.block_align() as usize;
+    let mut bufs = vec![vec![[0.0; 2]; BLOCK]; 16];
+    let mut bytes = vec![0; BLOCK * block_align];
+
+    loop {
+        let frames = {
+            let mut stems = bufs.iter_mut().map(|x| &mut x[..]).collect::<Vec<_>>();
+
+            playback.render_stems_f32(&mut stems)
+        };
+
+        for (track, out) in outputs.iter_mut() {
+            for (out, frame) in bytes.chunks_exact_mut(block_align).zip(&bufs[*track][..frames]) {
+                sample_format.encode(frame[0], out);
+                sample_format.encode(frame[1], &mut out[block_align / 2..]);
+            }
+
+            out.write_all(&bytes[..frames * block_align])?;
+        }
 
-        frames_done += frames;
+        progress.update(&playback, frames);
 
-        if frames_done == total_frames as usize {
+        if frames < BLOCK {
             break;
         }
     }
 
-    eprintln!();
+    progress.finish();
+
+    for (_, out) in outputs {
+        out.finish()?;
+    }
+
+    Ok(())
+}
+
+fn info(input: &Path) -> Result<()> {
+    let bnk = SoundBank::builtin()?;
+    let org = load(input)?;
+
+    println!("Format:   Org-0{}", org.version as u8 as char);
+    println!("Tempo:    {} ms/tick", org.time.wait);
+    println!("Display:  {}", org.display);
+
+    let (start, end) = (org.time.loop_range.start, org.time.loop_range.end);
+    let (bar_start, _, _) = org.display.bar_beat_step(start);
+    let (bar_end, _, _) = org.display.bar_beat_step(end);
+
+    println!("Loop:     ticks {}..{} (bars {}..{})", start, end, bar_start + 1, bar_end + 1);
+
+    if org.validate(&bnk).iter().all(|x| x.severity != Severity::Error) {
+        let mut playback = PlaybackEngine::new(org, &bnk)?;
+        playback.loops = Some(0);
+
+        match playback.get_total_samples() {
+            Some(frames) => println!("Length:   {}", format_time(frames as u64, playback.get_output_format().sample_rate)),
+            None         => println!("Length:   too long to count")
+        }
+
+        print_tracks(playback.song());
+    } else {
+        print_tracks(&org);
+    }
 
     Ok(())
 }
 
-fn print_wav_header(samples: u32) -> io::Result<()> {
-    let data_size = 4 * samples;
-    let riff_size = 36 + data_size;
+fn print_tracks(org: &Song) {
+    println!("Tracks:");
+
+    for (i, track) in org.tracks.iter().enumerate() {
+        if track.notes.is_empty() {
+            continue;
+        }
+
+        let kind = if i < 8 { "wave" } else { "drum" };
+        let pipi = if track.inst.pipi != 0 { ", pi" } else { "" };
+
+        println!("    {:2}: {} {:3}, freq {:4}{}, {} notes",
+            i, kind, track.inst.inst, track.inst.freq, pipi, track.notes.len());
+    }
+}
+
+fn validate(files: &[std::path::PathBuf]) -> Result<()> {
+    let bnk = SoundBank::builtin()?;
+    let mut failed = Vec::new();
+
+    for path in files {
+        let org = match load(path) {
+            Ok(org) => org,
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                failed.push(path);
+                continue;
+            }
+        };
+
+        let issues = org.validate(&bnk);
 
-    let format = WAVEFORMATEX::new(2, 44100, 16);
+        for issue in issues.iter() {
+            println!("{}: {}", path.display(), issue);
+        }
 
-    let stdout = io::stdout();
-    let mut out = stdout.lock();
+        if issues.iter().any(|x| x.severity == Severity::Error) {
+            failed.push(path);
+        }
+    }
 
-    out.write_all(b"RIFF")?;
-    out.write_u32::<LE>(riff_size)?;
-    out.write_all(b"WAVE")?;
-    out.write_all(&mut format.to_bytes())?;
-    out.write_all(b"data")?;
-    out.write_u32::<LE>(data_size)?;
+    if !failed.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("{} of {} file(s) failed validation", failed.len(), files.len())).into());
+    }
 
     Ok(())
 }
 
-#[allow(non_snake_case)]
-#[repr(C)]
-struct WAVEFORMATEX {
-    // Must be 1
-    wFormatTag: u16,
-    // Must be 2
-    nChannels: u16,
-    // Must be 44100
-    nSamplesPerSec: u32,
-    // Must be 44100 * nBlockAlign
-    nAvgBytesPerSec: u32,
-    // Must be nChannels * wBitsPerSample / 8
-    nBlockAlign: u16,
-    // Must be 8
-    wBitsPerSample: u16,
-}
-
-#[allow(non_snake_case)]
-impl WAVEFORMATEX {
-    const fn new(nChannels: u16, nSamplesPerSec: u32, wBitsPerSample: u16) -> Self {
-        let nBlockAlign = nChannels * (wBitsPerSample / 8);
-        let nAvgBytesPerSec = nSamplesPerSec * nBlockAlign as u32;
-
-        WAVEFORMATEX {
-            wFormatTag: 1,
-            nChannels,
-            nSamplesPerSec,
-            nAvgBytesPerSec,
-            nBlockAlign,
-            wBitsPerSample
-        }
-    }
-
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut out = Vec::with_capacity(24);
-        out.write_all(b"fmt ").unwrap();
-        out.write_u32::<LE>(16).unwrap();
-        out.write_u16::<LE>(self.wFormatTag).unwrap();
-        out.write_u16::<LE>(self.nChannels).unwrap();
-        out.write_u32::<LE>(self.nSamplesPerSec).unwrap();
-        out.write_u32::<LE>(self.nAvgBytesPerSec).unwrap();
-        out.write_u16::<LE>(self.nBlockAlign).unwrap();
-        out.write_u16::<LE>(self.wBitsPerSample).unwrap();
-        out
+fn export(opts: cli::Export) -> Result<()> {
+    let bnk = SoundBank::builtin()?;
+    let mut org = load_valid(&opts.input, &bnk, false)?;
+
+    if let Some(version) = opts.version {
+        org.version = version;
     }
+
+    let mut out = BufWriter::new(File::create(&opts.output)?);
+
+    org.write_to(&mut out)?;
+    out.flush()?;
+
+    Ok(())
 }
 
 /*
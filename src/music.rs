@@ -0,0 +1,247 @@
+use crate::bnk::SoundBank;
+use crate::error::Result;
+use crate::org::Song;
+use crate::playback::{Fade, Frame, PlaybackEngine};
+
+// How to get from the song that's playing to the next one
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Transition {
+    // Stop the old song and start the new one right away
+    Cut,
+    // Fade the old song out while the new one fades in
+    Crossfade(Fade),
+    // Fade the old song out, then start the new one
+    FadeOut(Fade)
+}
+
+// A copy of a song that's being left, to carry on with at full volume later.
+fn saved(engine: &PlaybackEngine) -> PlaybackEngine {
+    let mut engine = engine.clone();
+    engine.cancel_fade();
+    engine
+}
+
+// A fade in, counted in frames
+#[derive(Copy, Clone, Debug)]
+struct FadeIn {
+    fade: Fade,
+    done: u32,
+    len: u32
+}
+
+impl FadeIn {
+    fn new(fade: Fade, sample_rate: u32) -> FadeIn {
+        FadeIn { fade, done: 0, len: (fade.seconds.max(0.0) * sample_rate as f32) as u32 }
+    }
+}
+
+// Plays one song at a time, like Cave Story's music system. Changing songs
+// remembers where the old one was, so it can be picked up again later
+// (<CMU and <RMU), and songs can be faded out on their own (<FMU).
+pub struct MusicManager {
+    bank: SoundBank,
+    sample_rate: u32,
+    current: Option<PlaybackEngine>,
+    // Waits for the outgoing song to finish before it starts
+    waiting: bool,
+    fade_in: Option<FadeIn>,
+    // Still fading out after a transition
+    outgoing: Option<PlaybackEngine>,
+    // The song before the current one, as it was when we left it
+    previous: Option<PlaybackEngine>,
+    // Reused between render calls
+    scratch: Vec<Frame>
+}
+
+impl MusicManager {
+    pub fn new(bank: SoundBank, sample_rate: u32) -> MusicManager {
+        MusicManager {
+            bank,
+            sample_rate,
+            current: None,
+            waiting: false,
+            fade_in: None,
+            outgoing: None,
+            previous: None,
+            scratch: Vec::new()
+        }
+    }
+
+    pub fn bank(&self) -> &SoundBank {
+        &self.bank
+    }
+
+    pub fn current(&self) -> Option<&PlaybackEngine> {
+        self.current.as_ref()
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut PlaybackEngine> {
+        self.current.as_mut()
+    }
+
+    pub fn previous(&self) -> Option<&PlaybackEngine> {
+        self.previous.as_ref()
+    }
+
+    // Switches to a new song, which loops forever from the start.
+    // The song that was playing is saved as the previous one.
+    // Returns the new engine, for changing its settings before it plays.
+    // Songs that fail validation are turned away with Error::Invalid, and
+    // whatever was playing carries on as if nothing happened.
+    pub fn play(&mut self, song: Song, transition: Transition) -> Result<&mut PlaybackEngine> {
+        let mut engine = PlaybackEngine::new(song, &self.bank)?;
+
+        engine.loops = None;
+        engine.set_sample_rate(self.sample_rate);
+
+        if let Some(current) = self.current.as_ref().filter(|_| !self.waiting) {
+            self.previous = Some(saved(current));
+        }
+
+        self.switch_to(engine, transition);
+
+        Ok(self.current.as_mut().expect("a song was just started"))
+    }
+
+    // Goes back to the previous song, right where it was left.
+    // The current song becomes the previous one, so doing this twice swaps back.
+    // Returns false if there's no previous song.
+    pub fn resume_previous(&mut self, transition: Transition) -> bool {
+        let engine = match self.previous.take() {
+            Some(engine) => engine,
+            None         => return false
+        };
+
+        self.previous = self.current.as_ref().filter(|_| !self.waiting).map(saved);
+        self.switch_to(engine, transition);
+
+        true
+    }
+
+    // Fades the current song out and stops it.
+    pub fn fade_out(&mut self, fade: Fade) {
+        if let Some(current) = &mut self.current {
+            current.fade_out(fade);
+        }
+    }
+
+    // Stops everything straight away. The previous song is kept.
+    pub fn stop(&mut self) {
+        self.current = None;
+        self.outgoing = None;
+        self.fade_in = None;
+        self.waiting = false;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.current.as_ref().is_some_and(|x| !x.is_finished()) || self.outgoing.is_some()
+    }
+
+    fn switch_to(&mut self, engine: PlaybackEngine, transition: Transition) {
+        // A song that was still waiting for its turn never gets to play
+        let waiting = self.waiting;
+        let old = self.current.take().filter(|_| !waiting);
+
+        self.current = Some(engine);
+        self.fade_in = None;
+        self.waiting = false;
+
+        match (old, transition) {
+            (_, Transition::Cut) => self.outgoing = None,
+            // Anything that was still fading out from before gets cut off
+            (Some(mut old), Transition::Crossfade(fade)) => {
+                old.fade_out(fade);
+                self.outgoing = Some(old);
+                self.fade_in = Some(FadeIn::new(fade, self.sample_rate));
+            }
+            (None, Transition::Crossfade(fade)) => self.fade_in = Some(FadeIn::new(fade, self.sample_rate)),
+            (Some(mut old), Transition::FadeOut(fade)) => {
+                old.fade_out(fade);
+                self.outgoing = Some(old);
+                self.waiting = true;
+            }
+            (None, Transition::FadeOut(_)) => self.waiting = self.outgoing.is_some()
+        }
+    }
+
+    // Fills all of buf with 1.0 as full scale, padding with silence once nothing is playing.
+    // Songs change over at the start of a call, so smaller buffers make for tighter transitions.
+    pub fn render(&mut self, buf: &mut [Frame]) {
+        buf.fill([0.0; 2]);
+
+        let mut scratch = std::mem::take(&mut self.scratch);
+        scratch.resize(buf.len(), [0.0; 2]);
+
+        if let Some(outgoing) = &mut self.outgoing {
+            let frames = outgoing.render_f32(&mut scratch);
+
+            for (frame, src) in buf.iter_mut().zip(&scratch[..frames]) {
+                frame[0] += src[0];
+                frame[1] += src[1];
+            }
+
+            if frames < buf.len() {
+                self.outgoing = None;
+            }
+        }
+
+        if self.outgoing.is_none() {
+            self.waiting = false;
+        }
+
+        if let (false, Some(current)) = (self.waiting, &mut self.current) {
+            let frames = current.render_f32(&mut scratch);
+
+            for (frame, src) in buf.iter_mut().zip(&scratch[..frames]) {
+                let gain = match &mut self.fade_in {
+                    Some(fade_in) if fade_in.done < fade_in.len => {
+                        // A fade out played backwards
+                        let t = fade_in.done as f32 / fade_in.len as f32;
+                        fade_in.done += 1;
+
+                        fade_in.fade.curve.gain(1.0 - t)
+                    }
+                    _ => 1.0
+                };
+
+                frame[0] += src[0] * gain;
+                frame[1] += src[1] * gain;
+            }
+        }
+
+        self.scratch = scratch;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::playback::FadeCurve;
+    use crate::org::test_song;
+
+    #[test]
+    fn invalid_song_leaves_state_alone() {
+        let mut music = MusicManager::new(SoundBank::builtin().unwrap(), 44100);
+        let mut buf = vec![[0.0; 2]; 4096];
+
+        music.play(test_song("Access"), Transition::Cut).unwrap();
+        music.render(&mut buf);
+
+        let before = music.current().unwrap().get_position();
+
+        let mut bad = test_song("Gravity");
+        bad.tracks.iter_mut().find(|x| !x.notes.is_empty()).unwrap().notes[0].pan = 40;
+
+        match music.play(bad, Transition::Crossfade(Fade { seconds: 1.0, curve: FadeCurve::Linear })) {
+            Err(Error::Invalid(_)) => {}
+            Err(e) => panic!("wrong error: {}", e),
+            Ok(_)  => panic!("invalid song was played")
+        }
+
+        assert_eq!(music.current().unwrap().get_position(), before);
+        assert_eq!(music.current().unwrap().song().time.wait, test_song("Access").time.wait);
+        assert!(music.previous().is_none());
+        assert!(music.outgoing.is_none() && music.fade_in.is_none() && !music.waiting);
+    }
+}
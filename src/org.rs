@@ -1,5 +1,5 @@
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub enum Version {
     // Can't find any files with this signature,
     // But apparently these files had no Pi flag.
@@ -9,7 +9,7 @@ pub enum Version {
     Extended = b'3'
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct LoopRange {
     // inclusive
     pub start: i32,
@@ -17,13 +17,48 @@ pub struct LoopRange {
     pub end: i32
 }
 
-#[derive(Debug)]
+// Only used by Org Maker to draw the grid, but handy for anything that wants bars and beats.
+#[derive(Copy, Clone, Debug)]
 pub struct Display {
+    // beats per bar
     pub beats: u8,
+    // ticks per beat
     pub steps: u8
 }
 
-#[derive(Debug)]
+impl Default for Display {
+    // What Org Maker uses for a new song
+    fn default() -> Self {
+        Display { beats: 4, steps: 4 }
+    }
+}
+
+impl Display {
+    pub fn ticks_per_beat(&self) -> i32 {
+        // Broken files can have zeroes here
+        self.steps.max(1) as i32
+    }
+
+    pub fn ticks_per_bar(&self) -> i32 {
+        self.beats.max(1) as i32 * self.ticks_per_beat()
+    }
+
+    // Splits a tick position into (bar, beat, step), all counting from 0.
+    pub fn bar_beat_step(&self, pos: i32) -> (i32, i32, i32) {
+        let bar  = pos.div_euclid(self.ticks_per_bar());
+        let rest = pos.rem_euclid(self.ticks_per_bar());
+
+        (bar, rest / self.ticks_per_beat(), rest % self.ticks_per_beat())
+    }
+}
+
+impl std::fmt::Display for Display {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} beats/bar, {} steps/beat", self.beats, self.steps)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Timing {
     pub wait: u16,
     pub loop_range: LoopRange
@@ -38,6 +73,7 @@ pub struct Instrument {
     pub notes: u16
 }
 
+#[derive(Clone)]
 pub struct Track {
     pub inst: Instrument,
     pub notes: Vec<Note>
@@ -49,7 +85,6 @@ impl std::fmt::Debug for Track {
     }
 }
 
-#[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct Note {
     pub pos: i32,
@@ -59,113 +94,98 @@ pub struct Note {
     pub pan: u8
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Song {
     pub version: Version,
+    pub display: Display,
     pub time: Timing,
-    pub tracks: [Track; 16]
+    pub tracks: [Track; 16],
+    // Anything after the last track. Org Maker never writes this.
+    pub trailing: Vec<u8>
 }
 
-use byteorder::{LE, ReadBytesExt};
+use crate::error::{Error, Result};
+use crate::reader::Reader;
+use byteorder::{ByteOrder, LE, WriteBytesExt};
+use std::convert::TryInto;
 use std::io;
 
 impl Song {
-    pub fn load_from<R: io::Read>(mut f: R) -> io::Result<Song> {
+    pub fn load_from<R: io::Read>(f: R) -> Result<Song> {
+        let mut f = Reader::new(f);
         let mut magic = [0; 6];
 
-        f.read_exact(&mut magic)?;
+        f.bytes(&mut magic)?;
 
         let version =
             match &magic {
                 b"Org-01" => Version::Beta,
                 b"Org-02" => Version::Main,
                 b"Org-03" => Version::Extended,
-                _         => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid magic number"))
+                _         => return Err(Error::BadMagic { offset: 0, expected: "Org-01, Org-02 or Org-03", found: magic.to_vec() })
             };
 
-        let wait  = f.read_u16::<LE>()?;
-        let _bpm  = f.read_u8()?;
-        let _spb  = f.read_u8()?;
-        let start = f.read_i32::<LE>()?;
-        let end   = f.read_i32::<LE>()?;
+        let wait  = f.u16()?;
+        let beats = f.u8()?;
+        let steps = f.u8()?;
+        let start = f.i32()?;
+        let end   = f.i32()?;
 
-        use std::mem::MaybeUninit as Mu;
-
-        let mut insts: [Mu<Instrument>; 16] = unsafe {
-            Mu::uninit().assume_init()
-        };
+        let mut insts = [Instrument { freq: 0, inst: 0, pipi: 0, notes: 0 }; 16];
 
         for i in insts.iter_mut() {
-            let freq  = f.read_u16::<LE>()?;
-            let inst  = f.read_u8()?;
-            let pipi  = f.read_u8()?;
-            let notes = f.read_u16::<LE>()?;
+            let freq  = f.u16()?;
+            let inst  = f.u8()?;
+            let pipi  = f.u8()?;
+            let notes = f.u16()?;
 
-            *i = Mu::new(Instrument {
+            *i = Instrument {
                 freq,
                 inst,
                 pipi,
                 notes
-            });
-        }
-
-        let insts: [Instrument; 16] = unsafe {
-            std::mem::transmute(insts)
-        };
-
-        let mut tracks: [Mu<Track>; 16] = unsafe {
-            Mu::uninit().assume_init()
-        };
-
-        for (i, t) in tracks.iter_mut().enumerate() {
-            let count = insts[i].notes as usize;
-
-            #[repr(C)]
-            #[derive(Copy, Clone)]
-            struct UninitNote {
-                pos: Mu<i32>,
-                key: Mu<u8>,
-                len: Mu<u8>,
-                vol: Mu<u8>,
-                pan: Mu<u8>
-            }
-
-            let mut notes: Vec<UninitNote> = unsafe {
-                vec![Mu::uninit().assume_init(); count]
             };
+        }
 
-            for note in notes.iter_mut() {
-                note.pos = Mu::new(f.read_i32::<LE>()?);
-            }
-
-            for note in notes.iter_mut() {
-                note.key = Mu::new(f.read_u8()?);
-            }
+        let mut tracks = Vec::with_capacity(16);
 
-            for note in notes.iter_mut() {
-                note.len = Mu::new(f.read_u8()?);
-            }
+        for inst in insts.iter() {
+            let count = inst.notes as usize;
 
-            for note in notes.iter_mut() {
-                note.vol = Mu::new(f.read_u8()?);
-            }
+            // The note count comes straight from the file, so make sure the data
+            // is actually there before allocating anything based on it.
+            let pos = f.vec(count * 4)?;
+            let key = f.vec(count)?;
+            let len = f.vec(count)?;
+            let vol = f.vec(count)?;
+            let pan = f.vec(count)?;
 
-            for note in notes.iter_mut() {
-                note.pan = Mu::new(f.read_u8()?);
-            }
+            let notes = (0..count).map(|i|
+                Note {
+                    pos: LE::read_i32(&pos[i * 4..]),
+                    key: key[i],
+                    len: len[i],
+                    vol: vol[i],
+                    pan: pan[i]
+                }
+            ).collect();
 
-            *t = Mu::new(Track {
-                inst: insts[i],
-                notes: unsafe { std::mem::transmute(notes) }
-            });
+            tracks.push(Track { inst: *inst, notes });
         }
 
-        let tracks = unsafe {
-            std::mem::transmute(tracks)
+        let tracks = match tracks.try_into() {
+            Ok(tracks) => tracks,
+            Err(_)     => unreachable!()
         };
 
+        let trailing = f.rest()?;
+
         let song = Song {
             version,
+            display: Display {
+                beats,
+                steps
+            },
             time: Timing {
                 wait,
                 loop_range: LoopRange {
@@ -173,9 +193,104 @@ impl Song {
                     end
                 }
             },
-            tracks
+            tracks,
+            trailing
         };
 
         Ok(song)
     }
 }
+
+impl Song {
+    // Writes the song in the same layout load_from reads, so an untouched song
+    // comes back out byte for byte.
+    pub fn write_to<W: io::Write>(&self, mut f: W) -> Result<()> {
+        for (track, t) in self.tracks.iter().enumerate() {
+            if t.notes.len() > u16::MAX as usize {
+                return Err(Error::TooManyNotes { track, count: t.notes.len() });
+            }
+        }
+
+        f.write_all(b"Org-0")?;
+        f.write_u8(self.version as u8)?;
+
+        f.write_u16::<LE>(self.time.wait)?;
+        f.write_u8(self.display.beats)?;
+        f.write_u8(self.display.steps)?;
+        f.write_i32::<LE>(self.time.loop_range.start)?;
+        f.write_i32::<LE>(self.time.loop_range.end)?;
+
+        for t in self.tracks.iter() {
+            f.write_u16::<LE>(t.inst.freq)?;
+            f.write_u8(t.inst.inst)?;
+            f.write_u8(t.inst.pipi)?;
+            // The note list is authoritative, in case someone edited it.
+            f.write_u16::<LE>(t.notes.len() as u16)?;
+        }
+
+        for t in self.tracks.iter() {
+            for note in t.notes.iter() {
+                f.write_i32::<LE>(note.pos)?;
+            }
+
+            for note in t.notes.iter() {
+                f.write_u8(note.key)?;
+            }
+
+            for note in t.notes.iter() {
+                f.write_u8(note.len)?;
+            }
+
+            for note in t.notes.iter() {
+                f.write_u8(note.vol)?;
+            }
+
+            for note in t.notes.iter() {
+                f.write_u8(note.pan)?;
+            }
+        }
+
+        f.write_all(&self.trailing)?;
+
+        Ok(())
+    }
+}
+
+// One of the songs in orgs/, by name without the extension.
+#[cfg(test)]
+pub(crate) fn test_song(name: &str) -> Song {
+    let path = format!("{}/orgs/{}.org", env!("CARGO_MANIFEST_DIR"), name);
+
+    Song::load_from(io::BufReader::new(std::fs::File::open(path).unwrap())).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn round_trip() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/orgs");
+        let mut count = 0;
+
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+
+            if path.extension().is_none_or(|x| x != "org") {
+                continue;
+            }
+
+            let bytes = fs::read(&path).unwrap();
+            let song = Song::load_from(&bytes[..]).unwrap();
+
+            let mut written = Vec::new();
+            song.write_to(&mut written).unwrap();
+
+            assert!(written == bytes, "{} changed on the way through", path.display());
+            count += 1;
+        }
+
+        assert!(count > 0, "no songs in {}", dir);
+    }
+}
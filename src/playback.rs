@@ -1,14 +1,28 @@
 #![allow(dead_code, unused_imports)]
 use crate::bnk::SoundBank;
-use crate::org::Song as Organya;
+use crate::error::{Error, Result};
+use crate::org::{Note, Song as Organya};
 use crate::stuff::*;
+use crate::validate::Severity;
 use crate::wav::*;
 
+use std::convert::TryFrom;
 use std::mem::MaybeUninit;
+use std::ops::Range;
+use std::panic;
+use std::sync::mpsc;
+use std::thread;
 
+// Cloning makes a snapshot that plays on from exactly the same spot.
+#[derive(Clone)]
 pub struct PlaybackEngine {
     song: Organya,
     mute: [bool; 16],
+    // Every track's notes, sorted by position. Notes at the same position keep
+    // their file order, and only the first of them plays, like in Org Maker.
+    events: [Vec<Note>; 16],
+    // Index of the next note to look at in each track
+    cursors: [usize; 16],
     lengths: [u8; 8],
     swaps: [usize; 8],
     keys: [u8; 8],
@@ -16,14 +30,283 @@ pub struct PlaybackEngine {
     output_format: WavFormat,
     play_pos: i32,
     frames_this_tick: usize,
+    // Length of the current tick
     frames_per_tick: usize,
-    frames_done: u32,
-    pub loops: usize,
+    // Thousandths of a frame carried over from earlier ticks
+    tick_remainder: u64,
+    // Only used with TickTiming::Timer
+    timer: Option<Timer>,
+    // Ticks since the start, counting every loop
+    ticks_played: u64,
+    // Times we've gone back to the loop start
+    loop_count: usize,
+    // Frames left to play once the last loop is done
+    tail_left: Option<u32>,
+    // Times to repeat the loop after the first pass, or None to loop forever
+    pub loops: Option<usize>,
+    // Seconds to keep playing after the last loop
     pub extra: u32,
+    // Fades out after the last loop, stretching the tail to fit if needed
+    pub fade: Option<Fade>,
+    // The fade that's currently happening, if any
+    fading: Option<Fading>,
+    pub interpolation: Interpolation,
+    pub mixing: Mixing,
+    pub timing: TickTiming,
+    // Threads to mix on. The output is the same no matter how many there are.
+    pub threads: usize,
+    // Reused between render_to calls
+    bus: Vec<Frame>,
+    // What each buffer adds to the mix, when mixing on several threads
+    scratch: Vec<Vec<Frame>>,
+    // Started the first time they're needed, and kept until the engine goes away
+    workers: Workers,
+}
+
+// Shape of the volume curve during a fade out
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum FadeCurve {
+    #[default]
+    Linear,
+    // Falls linearly in dB to -60 dB over the fade, then cuts to silence
+    Exponential,
+    // Keeps the power constant if mixed with a matching fade in, for crossfades
+    EqualPower
+}
+
+impl FadeCurve {
+    // Gain at t, going from 0 at the start of the fade to 1 at the end.
+    pub(crate) fn gain(self, t: f32) -> f32 {
+        match self {
+            FadeCurve::Linear      => 1.0 - t,
+            FadeCurve::Exponential => if t < 1.0 { f32::powf(10.0, -3.0 * t) } else { 0.0 },
+            FadeCurve::EqualPower  => f32::cos(t * std::f32::consts::FRAC_PI_2)
+        }
+    }
+}
+
+impl std::str::FromStr for FadeCurve {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "linear"      => Ok(FadeCurve::Linear),
+            "exponential" => Ok(FadeCurve::Exponential),
+            "equal-power" => Ok(FadeCurve::EqualPower),
+            _             => Err(())
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Fade {
+    pub seconds: f32,
+    pub curve: FadeCurve
+}
+
+impl Fade {
+    fn frames(&self, sample_rate: u32) -> u32 {
+        (self.seconds.max(0.0) * sample_rate as f32) as u32
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Fading {
+    curve: FadeCurve,
+    done: u32,
+    len: u32
+}
+
+impl Fading {
+    fn gain(&self) -> f32 {
+        if self.done >= self.len {
+            0.0
+        } else {
+            self.curve.gain(self.done as f32 / self.len as f32)
+        }
+    }
+}
+
+// How long each tick lasts in frames
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum TickTiming {
+    // sample_rate * wait / 1000 frames, with the fractions carried over to
+    // the next tick, so every tick starts on the exact frame it should.
+    #[default]
+    Exact,
+    // Round every tick down to a whole number of frames, like older versions did.
+    // Songs play slightly fast, and drift further out the longer they go on.
+    Truncated,
+    // Start ticks when a Windows multimedia timer would have gone off, and
+    // DirectSound would have picked up the change. Uneven, like the real thing.
+    Timer(TimerModel)
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct TimerModel {
+    // The same seed always gives the same render
+    pub seed: u64,
+    // The timer goes off up to this many ms early or late
+    pub jitter_ms: u32,
+    // DirectSound only picks up changes once per update, every this many ms
+    pub buffer_ms: u32
+}
+
+impl Default for TimerModel {
+    fn default() -> TimerModel {
+        TimerModel { seed: 0, jitter_ms: 1, buffer_ms: 10 }
+    }
+}
+
+// xorshift64, which is plenty for timer jitter and gives the same numbers everywhere.
+#[derive(Clone, Debug)]
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // An all zero state would stay zero forever
+        XorShift(if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed })
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
+
+// Org Maker runs the song from a periodic timeSetEvent callback, so ticks are
+// scheduled in whole milliseconds and don't drift, but each one fires a little off.
+#[derive(Clone, Debug)]
+struct Timer {
+    model: TimerModel,
+    rng: XorShift,
+    // When the timer is due to go off next, in ms. The tempo can change along the way.
+    due: u64,
+    // When the last tick fired, in ms
+    fired: u64,
+    // Frame the current tick became audible on
+    onset: u64
+}
+
+impl Timer {
+    fn new(model: TimerModel) -> Timer {
+        Timer { model, rng: XorShift::new(model.seed), due: 0, fired: 0, onset: 0 }
+    }
+
+    // Moves on to the next tick, returning how many frames the current one lasts.
+    fn next_tick(&mut self, sample_rate: u32, wait: u16) -> usize {
+        let jitter = self.model.jitter_ms as i64;
+
+        self.due += wait as u64;
+
+        let offset = (self.rng.next() % (jitter as u64 * 2 + 1)) as i64 - jitter;
+        let due = self.due as i64 + offset;
+
+        // Callbacks can't overtake each other
+        self.fired = (due.max(0) as u64).max(self.fired);
+
+        let audible = match self.model.buffer_ms {
+            0      => self.fired,
+            buffer => self.fired.div_ceil(buffer as u64) * buffer as u64
+        };
+
+        let onset = audible * sample_rate as u64 / 1000;
+        let frames = onset - self.onset;
+
+        self.onset = onset;
+
+        frames as usize
+    }
+}
+
+impl std::str::FromStr for TickTiming {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "exact"     => Ok(TickTiming::Exact),
+            "truncated" => Ok(TickTiming::Truncated),
+            "timer"     => Ok(TickTiming::Timer(TimerModel::default())),
+            _           => Err(())
+        }
+    }
+}
+
+// How sources are summed on the mixing bus
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum Mixing {
+    // Sum everything in floating point and clip once at the end
+    #[default]
+    Float,
+    // Truncate and clip to 16 bits after every source, like older versions did.
+    // The result depends on the order sources are added in.
+    Saturating
+}
+
+impl std::str::FromStr for Mixing {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "float"      => Ok(Mixing::Float),
+            "saturating" => Ok(Mixing::Saturating),
+            _            => Err(())
+        }
+    }
+}
+
+// How samples get resampled to the output rate
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum Interpolation {
+    // Drops to the previous sample, like DirectSound's software mixer on old hardware
+    Nearest,
+    Linear,
+    Cosine,
+    Cubic,
+    // 4-tap Lanczos
+    Lanczos4,
+    // 6-tap Lanczos, closest to what DirectSound does on modern systems
+    #[default]
+    Lanczos6
+}
+
+impl std::str::FromStr for Interpolation {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "nearest"  => Ok(Interpolation::Nearest),
+            "linear"   => Ok(Interpolation::Linear),
+            "cosine"   => Ok(Interpolation::Cosine),
+            "cubic"    => Ok(Interpolation::Cubic),
+            "lanczos4" => Ok(Interpolation::Lanczos4),
+            "lanczos6" => Ok(Interpolation::Lanczos6),
+            _          => Err(())
+        }
+    }
 }
 
 impl PlaybackEngine {
-    pub fn new(song: Organya, samples: SoundBank) -> Self {
+    // Fails with Error::Invalid if validate finds any errors. Warnings are left to the caller.
+    pub fn new(song: Organya, samples: &SoundBank) -> Result<Self> {
+        for (track, t) in song.tracks.iter().enumerate() {
+            let count = if track < 8 { samples.wave100.len() / 256 } else { samples.samples.len() };
+
+            if t.inst.inst as usize >= count {
+                return Err(Error::InstrumentOutOfRange { track, inst: t.inst.inst });
+            }
+        }
+
+        // Anything validate calls an error would misbehave during playback
+        let issues = song.validate(samples);
+
+        if issues.iter().any(|x| x.severity == Severity::Error) {
+            return Err(Error::Invalid(issues));
+        }
 
         // Octave 0 Track 0 Swap 0
         // Octave 0 Track 1 Swap 0
@@ -72,15 +355,21 @@ impl PlaybackEngine {
                 );
         }
 
-        let frames_per_tick = ((44100.0 / 1000.0) * song.time.wait as f32) as usize;
+        let events = std::array::from_fn(|i| {
+            let mut notes = song.tracks[i].notes.clone();
+            notes.sort_by_key(|x| x.pos);
+            notes
+        });
 
-        PlaybackEngine {
+        Ok(PlaybackEngine {
             song,
             mute: [false; 16],
+            events,
+            cursors: [0; 16],
             lengths: [0; 8],
             swaps: [0; 8],
             keys: [255; 8],
-            track_buffers: unsafe { std::mem::transmute(buffers) },
+            track_buffers: unsafe { std::mem::transmute::<[MaybeUninit<RenderBuffer>; 136], [RenderBuffer; 136]>(buffers) },
             play_pos: 0,
             output_format: WavFormat {
                 channels: 2,
@@ -88,24 +377,216 @@ impl PlaybackEngine {
                 bit_depth: 16
             },
             frames_this_tick: 0,
-            frames_per_tick,
-            frames_done: 0,
-            loops: 1,
+            frames_per_tick: 0,
+            tick_remainder: 0,
+            timer: None,
+            ticks_played: 0,
+            loop_count: 0,
+            tail_left: None,
+            loops: Some(1),
             extra: 0,
-        }
+            fade: None,
+            fading: None,
+            interpolation: Interpolation::default(),
+            mixing: Mixing::default(),
+            timing: TickTiming::default(),
+            threads: 1,
+            bus: Vec::new(),
+            scratch: Vec::new(),
+            workers: Workers::new(),
+        })
     }
 
+    // Only moves the play head, notes that should be sounding aren't. See seek for that.
     #[allow(unused)]
     pub fn set_position(&mut self, position: i32) {
         self.play_pos = position;
+        self.move_cursors();
+    }
+
+    pub fn get_position(&self) -> i32 {
+        self.play_pos
+    }
+
+    // Takes effect immediately, including for notes that are already sounding.
+    pub fn set_track_muted(&mut self, track: usize, muted: bool) {
+        self.mute[track] = muted;
+    }
+
+    pub fn is_track_muted(&self, track: usize) -> bool {
+        self.mute[track]
+    }
+
+    // Mutes every track except this one.
+    pub fn solo_track(&mut self, track: usize) {
+        for (i, mute) in self.mute.iter_mut().enumerate() {
+            *mute = i != track;
+        }
+    }
+
+    // Milliseconds per tick, starting with the next tick.
+    pub fn set_tempo(&mut self, wait: u16) {
+        assert!(wait > 0);
+
+        self.song.time.wait = wait;
+    }
+
+    pub fn get_tempo(&self) -> u16 {
+        self.song.time.wait
+    }
+
+    pub fn song(&self) -> &Organya {
+        &self.song
+    }
+
+    // Jumps to `tick` ticks after the start, counting every pass through the loop.
+    // All the events up to there are played without mixing anything, so
+    // notes that are still sounding pick up right where they would be, give or take
+    // rounding. Right at a loop point in a waveform, that can change a single frame.
+    // Each tick skipped costs about the same, however long the notes in it are.
+    pub fn seek(&mut self, tick: u64) {
+        self.restart();
+
+        while self.ticks_played < tick && !self.is_finished() {
+            self.skip_in_tick(usize::MAX);
+        }
+    }
+
+    // Like seek, but to the exact frame.
+    pub fn seek_seconds(&mut self, seconds: f64) {
+        let mut frames = (seconds.max(0.0) * self.output_format.sample_rate as f64) as u64;
+
+        self.restart();
+
+        while frames > 0 && !self.is_finished() {
+            frames -= self.skip_in_tick(frames.min(usize::MAX as u64) as usize) as u64;
+        }
+    }
+
+    // Back to the very start, as if nothing had been played yet.
+    fn restart(&mut self) {
+        for buf in self.track_buffers.iter_mut() {
+            buf.reset();
+        }
+
+        self.lengths = [0; 8];
+        self.swaps = [0; 8];
+        self.keys = [255; 8];
+        self.play_pos = 0;
+        self.move_cursors();
+        self.frames_this_tick = 0;
+        self.frames_per_tick = 0;
+        self.tick_remainder = 0;
+        self.timer = None;
+        self.ticks_played = 0;
+        self.loop_count = 0;
+        self.tail_left = None;
+        self.fading = None;
+    }
+
+    // Plays up to `frames` frames without mixing, stopping early at the end of the tick.
+    // Returns how many frames were skipped.
+    fn skip_in_tick(&mut self, frames: usize) -> usize {
+        if self.frames_this_tick == 0 {
+            self.begin_tick();
+        }
+
+        let mut n = frames.min(self.frames_per_tick.saturating_sub(self.frames_this_tick));
+
+        if let Some(left) = self.tail_left.as_mut() {
+            n = n.min(*left as usize);
+            *left -= n as u32;
+        }
+
+        for buf in self.track_buffers.iter_mut() {
+            buf.skip(n, self.output_format.sample_rate);
+        }
+
+        if let Some(fading) = self.fading.as_mut() {
+            fading.done += n as u32;
+        }
+
+        self.frames_this_tick += n;
+
+        if self.frames_this_tick >= self.frames_per_tick {
+            self.next_tick();
+        }
+
+        n
+    }
+
+    // How many times playback has jumped back to the loop start so far.
+    pub fn get_loop_count(&self) -> usize {
+        self.loop_count
+    }
+
+    // True once the last loop and the tail after it have been played.
+    pub fn is_finished(&self) -> bool {
+        self.tail_left == Some(0)
+    }
+
+    // Starts fading out right away, regardless of loops. The song is
+    // finished once the fade is, or earlier if the tail runs out first.
+    pub fn fade_out(&mut self, fade: Fade) {
+        let len = fade.frames(self.output_format.sample_rate);
+
+        self.fading = Some(Fading { curve: fade.curve, done: 0, len });
+        self.tail_left = Some(self.tail_left.map_or(len, |x| x.min(len)));
     }
 
-    pub fn get_total_samples(&self) -> u32 {
-        let ticks_intro = self.song.time.loop_range.start;
-        let ticks_loop = self.song.time.loop_range.end - self.song.time.loop_range.start;
-        let ticks_total = ticks_intro + ticks_loop + (ticks_loop * self.loops as i32);
+    // Undoes fade_out. Songs that have already played their last loop still end after the tail.
+    pub fn cancel_fade(&mut self) {
+        let done = self.loops.is_some_and(|x| self.loop_count > x);
+
+        self.fading = None;
 
-        self.frames_per_tick as u32 * ticks_total as u32 + (self.extra * self.output_format.sample_rate)
+        if !done {
+            self.tail_left = None;
+        }
+    }
+
+    fn tail_frames(&self) -> u32 {
+        let rate = self.output_format.sample_rate;
+        let extra = (self.extra as u64 * rate as u64).min(u32::MAX as u64) as u32;
+
+        match self.fade {
+            Some(fade) => extra.max(fade.frames(rate)),
+            None       => extra
+        }
+    }
+
+    // Length of the whole render from the start. None if it loops forever,
+    // or if it's too long to count in a u32.
+    pub fn get_total_samples(&self) -> Option<u32> {
+        let loops = self.loops? as u64;
+
+        let ticks_intro = self.song.time.loop_range.start as i64 as u64;
+        let ticks_loop = (self.song.time.loop_range.end as i64 - self.song.time.loop_range.start as i64) as u64;
+        let ticks_total = ticks_loop.checked_mul(loops.checked_add(1)?)?.checked_add(ticks_intro)?;
+
+        let (rate, wait) = (self.output_format.sample_rate, self.song.time.wait);
+
+        let frames = match self.timing {
+            TickTiming::Exact     => ticks_total.checked_mul(rate as u64 * wait as u64)? / 1000,
+            TickTiming::Truncated => ticks_total.checked_mul(truncated_frames_per_tick(rate, wait) as u64)?,
+            TickTiming::Timer(model) => {
+                let mut timer = Timer::new(model);
+                let mut frames = 0;
+
+                for _ in 0..ticks_total {
+                    frames += timer.next_tick(rate, wait) as u64;
+
+                    // No point going any further
+                    if frames > u32::MAX as u64 {
+                        return None;
+                    }
+                }
+
+                frames
+            }
+        };
+
+        u32::try_from(frames).ok()?.checked_add(self.tail_frames())
     }
 
     fn get_active_buffer_for_track(&self, track: usize) -> usize {
@@ -152,32 +633,36 @@ impl PlaybackEngine {
         format!("Track {}, Octave {}, Buffer {}", buf_ns % 8, buf_ns / 8, buf >> 6)
     }
 
-    fn update_play_state(&mut self) {
-        // self.mute[0] = true;
-        // self.mute[1] = true;
-        // self.mute[2] = true;
-        // self.mute[3] = true;
-        // self.mute[4] = true;
-        // self.mute[5] = true;
-        // self.mute[6] = true;
-        // self.mute[7] = true;
-
-        // self.mute[8] = true;
-        // self.mute[9] = true;
-        // self.mute[10] = true;
-        // self.mute[11] = true;
-        // self.mute[12] = true;
-        // self.mute[13] = true;
-        // self.mute[14] = true;
-        // self.mute[15] = true;
+    // Points every cursor at the first note at or after the play head.
+    fn move_cursors(&mut self) {
+        let pos = self.play_pos;
+
+        for (cursor, notes) in self.cursors.iter_mut().zip(&self.events) {
+            *cursor = notes.partition_point(|x| x.pos < pos);
+        }
+    }
+
+    // The note at the play head on this track, if there is one.
+    // Has to be called once per track every tick, as the play head only moves forward.
+    fn next_event(&mut self, track: usize) -> Option<Note> {
+        let notes = &self.events[track];
+        let cursor = &mut self.cursors[track];
 
+        // Skips duplicates of last tick's note, and anything before the song starts
+        while *cursor < notes.len() && notes[*cursor].pos < self.play_pos {
+            *cursor += 1;
+        }
+
+        notes.get(*cursor).filter(|x| x.pos == self.play_pos).copied()
+    }
+
+    // Muted tracks still follow their notes, they just aren't mixed in,
+    // so unmuting picks up exactly where the track would be.
+    fn update_play_state(&mut self) {
         // For every wave track...
         for track in 0..8 {
-            if self.mute[track] { continue; }
-
             // Do we have a note for the current X pos?
-            if let Some(&note) =
-                self.song.tracks[track].notes.iter().find(|x| x.pos == self.play_pos) {
+            if let Some(note) = self.next_event(track) {
 
                 // New note (Pitch of 255 is a dummy value for volume/pan adjustments)
                 if note.key != 255 {
@@ -225,11 +710,9 @@ impl PlaybackEngine {
             }
 
             // Play lengths
-            if self.note_ended(track) {
-                if self.track_is_playing(track) {
-                    self.track_kill_note(track);
-                    self.track_stop_playing(track);
-                }
+            if self.note_ended(track) && self.track_is_playing(track) {
+                self.track_kill_note(track);
+                self.track_stop_playing(track);
             }
 
             self.lengths[track] = self.lengths[track].saturating_sub(1);
@@ -237,16 +720,11 @@ impl PlaybackEngine {
 
         // Drum notes
         for i in 8..16 {
-            if self.mute[i] { continue; }
-
             let j = i + 120;
 
-            let notes = &self.song.tracks[i].notes;
-
             // start a new note
             // note (hah) that drums are unaffected by length and pi values. This is the only case we have to handle.
-            if let Some(note) =
-                notes.iter().find(|x| x.pos == self.play_pos) {
+            if let Some(note) = self.next_event(i) {
 
                 // FIXME: Add constants for dummy values
                 if note.key != 255 {
@@ -269,204 +747,602 @@ impl PlaybackEngine {
         }
     }
 
+    pub fn get_output_format(&self) -> WavFormat {
+        self.output_format
+    }
+
+    pub fn get_sample_format(&self) -> SampleFormat {
+        SampleFormat::from_bit_depth(self.output_format.bit_depth).unwrap_or_default()
+    }
+
+    // Only affects render_bytes and the output format; render_to is always 16-bit.
+    pub fn set_sample_format(&mut self, format: SampleFormat) {
+        self.output_format.bit_depth = format.bit_depth();
+    }
+
+    // Meant to be called before rendering anything; the tick that's playing keeps its length.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        assert!(sample_rate > 0);
+
+        self.output_format.sample_rate = sample_rate;
+    }
+
+    // Returns how many frames were rendered. Anything less than buf.len() means the song is over.
     pub fn render_to(&mut self, buf: &mut [u32]) -> usize {
-        for (i, frame) in buf.iter_mut().enumerate() {
-            if self.frames_this_tick == 0 {
-                self.update_play_state()
+        let mut bus = std::mem::take(&mut self.bus);
+        bus.resize(buf.len(), [0.0; 2]);
+
+        let frames = self.render_bus(&mut bus[..buf.len()]);
+
+        // Every source has been added by now, so this is the only place we clip.
+        for (out, frame) in buf.iter_mut().zip(&bus[..frames]) {
+            let (l, r) = (frame[0] as i16, frame[1] as i16);
+
+            *out = (l as u32 & 0xFFFF) | ((r as u32) << 16);
+        }
+
+        self.bus = bus;
+
+        frames
+    }
+
+    // Floating point frames, with 1.0 as full scale. Nothing is clipped.
+    pub fn render_f32(&mut self, buf: &mut [Frame]) -> usize {
+        let frames = self.render_bus(buf);
+
+        for frame in buf[..frames].iter_mut() {
+            frame[0] /= 32768.0;
+            frame[1] /= 32768.0;
+        }
+
+        frames
+    }
+
+    // Interleaved little endian samples in the format set with set_sample_format,
+    // ready to go into a wav file. Returns the number of frames rendered.
+    pub fn render_bytes(&mut self, buf: &mut [u8]) -> usize {
+        let format = self.get_sample_format();
+        let block_align = self.output_format.block_align() as usize;
+
+        let mut bus = std::mem::take(&mut self.bus);
+        bus.resize(buf.len() / block_align, [0.0; 2]);
+
+        let frames = self.render_bus(&mut bus[..buf.len() / block_align]);
+
+        for (out, frame) in buf.chunks_exact_mut(block_align).zip(&bus[..frames]) {
+            for (out, &x) in out.chunks_exact_mut(block_align / 2).zip(frame) {
+                format.encode(x / 32768.0, out);
             }
+        }
 
-            mix(std::slice::from_mut(frame), self.output_format, &mut self.track_buffers);
+        self.bus = bus;
 
-            self.frames_done += 1;
-            self.frames_this_tick += 1;
+        frames
+    }
 
-            if self.frames_this_tick == self.frames_per_tick {
-                self.play_pos += 1;
+    // Renders every track into its own buffer, with the same timing as the full mix.
+    // stems must hold one slice per track, all the same length. Like render_f32, 1.0 is full scale.
+    pub fn render_stems_f32(&mut self, stems: &mut [&mut [Frame]]) -> usize {
+        assert_eq!(stems.len(), 16);
 
-                if self.play_pos == self.song.time.loop_range.end {
-                    self.play_pos = self.song.time.loop_range.start;
+        let len = stems.iter().map(|x| x.len()).min().unwrap_or(0);
 
-                    // if self.loops == 0 {
-                    //     // return i + 1;
-                    // }
-                    // else {
-                    //     self.loops -= 1;
-                    // }
+        let frames = self.render_with(len, |this, span| {
+            for stem in stems.iter_mut() {
+                stem[span.clone()].fill([0.0; 2]);
+            }
+
+            if this.threads > 1 {
+                for j in this.mix_parallel(span.len()) {
+                    add_frames(&mut stems[buffer_track(j)][span.clone()], &this.scratch[j], this.mixing);
+                }
+            } else {
+                for (j, buf) in this.track_buffers.iter_mut().enumerate() {
+                    let track = buffer_track(j);
+
+                    if this.mute[track] {
+                        buf.skip(span.len(), this.output_format.sample_rate);
+                        continue;
+                    }
+
+                    mix(&mut stems[track][span.clone()], this.output_format, std::slice::from_mut(buf), this.interpolation, this.mixing);
                 }
+            }
 
-                self.frames_this_tick = 0;
+            for stem in stems.iter_mut() {
+                this.apply_fade(&mut stem[span.clone()]);
             }
+        });
 
-            if self.frames_done == self.get_total_samples() {
-                return i + 1;
+        for stem in stems.iter_mut() {
+            for frame in stem[..frames].iter_mut() {
+                frame[0] /= 32768.0;
+                frame[1] /= 32768.0;
             }
         }
 
-        buf.len()
+        frames
     }
-}
 
-// TODO: Create a MixingBuffer or something...
-fn mix(dst: &mut [u32], dst_fmt: WavFormat, srcs: &mut [RenderBuffer]) {
-    let freq = dst_fmt.sample_rate as f64;
+    fn render_bus(&mut self, buf: &mut [Frame]) -> usize {
+        self.render_with(buf.len(), |this, span| {
+            let buf = &mut buf[span];
+
+            // mix adds on top of whatever is there
+            buf.fill([0.0; 2]);
 
-    for (_j, buf) in srcs.into_iter().enumerate() {
-        if buf.playing {
-            // index into sound samples
-            let advance = buf.frequency as f64 / freq;
-
-            let vol = centibel_to_scale(buf.volume);
-
-            let (pan_l, pan_r) =
-                match buf.pan.signum() {
-                     0 => (1.0, 1.0),
-                     1 => (centibel_to_scale(-buf.pan), 1.0),
-                    -1 => (1.0, centibel_to_scale(buf.pan)),
-                     _ => unsafe { std::hint::unreachable_unchecked() }
-                };
-
-            fn clamp<T: Ord>(v: T, limit: T) -> T {
-                if v > limit {
-                    limit
-                } else {
-                    v
+            if this.threads > 1 {
+                for j in this.mix_parallel(buf.len()) {
+                    add_frames(buf, &this.scratch[j], this.mixing);
                 }
-            }
+            } else {
+                for (j, src) in this.track_buffers.iter_mut().enumerate() {
+                    if this.mute[buffer_track(j)] {
+                        src.skip(buf.len(), this.output_format.sample_rate);
+                        continue;
+                    }
 
-            // s1: sample 1
-            // s2: sample 2
-            // sp: previous sample (before s1)
-            // sn: next sample (after s2)
-            // mu: position to interpolate for
-            fn cubic_interp(s1: f32, s2: f32, sp: f32, sn: f32, mu: f32) -> f32 {
-                let mu2 = mu * mu;
-                let a0 = sn - s2 - sp + s1;
-                let a1 = sp - s1 - a0;
-                let a2 = s2 - sp;
-                let a3 = s1;
-
-                a0*mu*mu2 + a1*mu2 + a2*mu + a3
+                    mix(buf, this.output_format, std::slice::from_mut(src), this.interpolation, this.mixing);
+                }
             }
 
-            use std::f32::consts::PI;
+            this.apply_fade(buf);
+        })
+    }
+
+    fn begin_tick(&mut self) {
+        self.start_tick();
+        self.update_play_state();
+
+        // A tick can end up too short for a frame of its own, but its events still happen.
+        while self.frames_per_tick == 0 && !self.is_finished() {
+            self.next_tick();
+            self.start_tick();
+            self.update_play_state();
+        }
+    }
 
-            fn sinc(x: f32) -> f32
-            {
-                if x.abs() <= f32::EPSILON
-                {
-                    return 1.0;
+    fn next_tick(&mut self) {
+        self.ticks_played += 1;
+        self.play_pos += 1;
+
+        if self.play_pos == self.song.time.loop_range.end {
+            self.play_pos = self.song.time.loop_range.start;
+            self.move_cursors();
+            self.loop_count += 1;
+
+            // The first pass through the loop isn't a repeat.
+            if self.tail_left.is_none() && self.loops.is_some_and(|x| self.loop_count > x) {
+                if let Some(fade) = self.fade {
+                    self.fade_out(fade);
                 }
 
-                let y = x * PI;
+                // Anything after the fade is over is silent
+                self.tail_left = Some(self.tail_frames());
+            }
+        }
+
+        self.frames_this_tick = 0;
+    }
 
-                y.sin() / y
+    fn start_tick(&mut self) {
+        let (rate, wait) = (self.output_format.sample_rate, self.song.time.wait);
+
+        self.frames_per_tick = match self.timing {
+            TickTiming::Exact => {
+                let exact = rate as u64 * wait as u64 + self.tick_remainder;
+
+                self.tick_remainder = exact % 1000;
+
+                (exact / 1000) as usize
             }
+            TickTiming::Truncated => truncated_frames_per_tick(rate, wait),
+            TickTiming::Timer(model) => self.timer.get_or_insert_with(|| Timer::new(model)).next_tick(rate, wait)
+        };
+    }
 
-            fn lanczos(x: f32, a: f32) -> f32
-            {
-                if x.abs() >= a
-                {
-                    return 0.0;
-                }
+    // Runs the song for up to `frames` frames. mix_span gets called for each
+    // stretch of frames where nothing changes, which is at most a tick long.
+    fn render_with<F: FnMut(&mut Self, Range<usize>)>(&mut self, frames: usize, mut mix_span: F) -> usize {
+        let mut i = 0;
+
+        while i < frames {
+            if self.is_finished() {
+                return i;
+            }
+
+            if self.frames_this_tick == 0 {
+                self.begin_tick();
+            }
+
+            let mut n = (frames - i).min(self.frames_per_tick.saturating_sub(self.frames_this_tick));
 
-                sinc(x) * sinc(x / a)
+            if let Some(left) = self.tail_left.as_mut() {
+                n = n.min(*left as usize);
+                *left -= n as u32;
             }
 
-            fn lanczos_interp(s1: f32, s2: f32, s3: f32, s4: f32, r: f32) -> f32
-            {
-                // assuming floor(x) = 0
-                (s4 * lanczos(r - -1.0, 2.0)) +
-                (s1 * lanczos(r, 2.0)) +
-                (s2 * lanczos(r - 1.0, 2.0)) +
-                (s3 * lanczos(r - 2.0, 2.0))
+            mix_span(self, i..i + n);
+
+            if let Some(fading) = self.fading.as_mut() {
+                fading.done += n as u32;
             }
 
-            fn lanczos_interp6(s1: f32, s2: f32, s3: f32, s4: f32, s5: f32, s6: f32, r: f32) -> f32
-            {
-                // assuming floor(x) = 0
-                (s5 * lanczos(r - -2.0, 3.0)) +
-                (s4 * lanczos(r - -1.0, 3.0)) +
-                (s1 * lanczos(r, 3.0)) +
-                (s2 * lanczos(r - 1.0, 3.0)) +
-                (s3 * lanczos(r - 2.0, 3.0)) +
-                (s6 * lanczos(r - 3.0, 3.0))
+            i += n;
+            self.frames_this_tick += n;
+
+            if self.frames_this_tick >= self.frames_per_tick {
+                self.next_tick();
             }
+        }
 
-            #[allow(unused_variables)]
+        frames
+    }
 
-            for (i, frame) in dst.iter_mut().enumerate() {
-                let pos = buf.position as usize + buf.base_pos;
-                // -1..1
+    // Works out what every playing buffer adds to the next `len` frames, spread over
+    // self.threads threads, and leaves it in self.scratch. Returns which buffers were mixed.
+    // Summing them is left to the caller and has to go in buffer order, so the result
+    // comes out exactly the same as mixing everything on one thread.
+    fn mix_parallel(&mut self, len: usize) -> Vec<usize> {
+        let (format, interpolation) = (self.output_format, self.interpolation);
 
-                // x
-                let s1 = (buf.sample.data[pos] as f32 - 128.0) / 128.0;
-                // x + 1
-                let s2 = (buf.sample.data[clamp(pos + 1, buf.base_pos + buf.len - 1)] as f32 - 128.0) / 128.0;
-                // x + 2
-                let s3 = (buf.sample.data[clamp(pos + 2, buf.base_pos + buf.len - 1)] as f32 - 128.0) / 128.0;
+        self.scratch.resize_with(self.track_buffers.len(), Vec::new);
 
-                // x - 1
-                let s4 = (buf.sample.data[pos.saturating_sub(1)] as f32 - 128.0) / 128.0;
-                // x - 2
-                let s5 = (buf.sample.data[pos.saturating_sub(2)] as f32 - 128.0) / 128.0;
+        let mut jobs = Vec::new();
 
-                // x + 3
-                let s6 = (buf.sample.data[clamp(pos + 3, buf.base_pos + buf.len - 1)] as f32 - 128.0) / 128.0;
-                use std::f32::consts::PI;
+        for (j, (buf, out)) in self.track_buffers.iter_mut().zip(self.scratch.iter_mut()).enumerate() {
+            if self.mute[buffer_track(j)] {
+                buf.skip(len, format.sample_rate);
+            } else if buf.playing {
+                out.clear();
+                out.resize(len, [0.0; 2]);
+                jobs.push((j, buf, out));
+            }
+        }
 
-                let r1 = buf.position.fract() as f32;
-                let r2 = (1.0 - f32::cos(r1 * PI)) / 2.0;
+        let mixed = jobs.iter().map(|x| x.0).collect();
 
-                //let s = s1; // No interp
-                //let s = s1 + (s2 - s1) * r1; // Linear interp
-                //let s = s1 * (1.0 - r2) + s2 * r2; // Cosine interp
-                //let s = cubic_interp(s1, s2, s4, s3, r1); // Cubic interp
-                //let s = lanczos_interp(s1, s2, s3, s4, r1);
-                let s = lanczos_interp6(s1, s2, s3, s4, s5, s6, r1);
-                // Ideally we want sinc/lanczos interpolation, since that's what DirectSound appears to use.
-
-                // -128..128
-                let sl = s * pan_l * vol * 32768.0;
-                let sr = s * pan_r * vol * 32768.0;
-
-                buf.position += advance;
-
-                if buf.position as usize >= buf.len {
-                    if buf.looping && buf.nloops != 1 {
-                        buf.position %= buf.len as f64;
-                        if buf.nloops != -1 {
-                            buf.nloops -= 1;
-                        }
-                    } else {
-                        buf.position = 0.0;
-                        buf.playing = false;
-                        break;
-                    }
-                }
+        let per_thread = jobs.len().div_ceil(self.threads).max(1);
+        let mut chunks = jobs.chunks_mut(per_thread);
+        let first = chunks.next();
 
-                // Signed
-                let (mut l, mut r) = ((*frame & 0xFFFF) as i16, (*frame >> 16) as i16);
+        self.workers.start(self.threads - 1);
 
-                // eprintln!("I: {:3} {:08} {:04X} {:04X} {:04X} {:04X} {:08X}", j, i, sl as i16, sr as i16, l, r, *frame);
+        let mut sent = 0;
 
-                l = l.saturating_add(sl as i16);
-                r = r.saturating_add(sr as i16);
+        for (worker, chunk) in self.workers.threads.iter().zip(chunks) {
+            // The worker only gets the jobs' lifetime back once it's reported in below
+            let batch = Batch { jobs: chunk.as_mut_ptr().cast(), len: chunk.len(), format, interpolation };
 
-                *frame = (l as u32 & 0xFFFF) | ((r as u32) << 16);
+            match worker.jobs.as_ref().map(|x| x.send(batch)) {
+                Some(Ok(())) => sent += 1,
+                Some(Err(mpsc::SendError(batch))) => batch.run(),
+                None => unreachable!("workers keep their channel until they're dropped")
+            }
+        }
 
-                // eprintln!("O: {:3} {:08} {:04X} {:04X} {:04X} {:04X} {:08X}", j, i, sl as i16, sr as i16, l, r, *frame);
+        // This thread does its share too, but has to wait for the others even if it panics
+        let ours = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            if let Some(chunk) = first {
+                mix_jobs(chunk, format, interpolation);
+            }
+        }));
 
+        let all_ok = (0..sent).fold(true, |ok, _| self.workers.done.recv() == Ok(true) && ok);
+
+        if let Err(e) = ours {
+            panic::resume_unwind(e);
+        }
+
+        assert!(all_ok, "a mixing thread panicked");
+
+        mixed
+    }
+
+    // Scales frames that are about to be rendered by the fade out, if there is one.
+    fn apply_fade(&self, frames: &mut [Frame]) {
+        if let Some(fading) = self.fading {
+            for (k, frame) in frames.iter_mut().enumerate() {
+                let gain = Fading { done: fading.done + k as u32, ..fading }.gain();
+
+                frame[0] *= gain;
+                frame[1] *= gain;
             }
         }
     }
 }
 
-pub fn centibel_to_scale(a: i32) -> f32 {
+// A buffer to mix, its number and where its part of the mix goes
+type Job<'a> = (usize, &'a mut RenderBuffer, &'a mut Vec<Frame>);
+
+// Adding onto zeroes keeps every sample exactly as mix made it.
+fn mix_jobs(jobs: &mut [Job], format: WavFormat, interpolation: Interpolation) {
+    for (_, buf, out) in jobs.iter_mut() {
+        mix(out, format, std::slice::from_mut(*buf), interpolation, Mixing::Float);
+    }
+}
+
+// Jobs handed to a worker. They really borrow from the engine, which
+// mix_parallel doesn't let go of until the worker says it's done with them.
+struct Batch {
+    jobs: *mut Job<'static>,
+    len: usize,
+    format: WavFormat,
+    interpolation: Interpolation
+}
+
+unsafe impl Send for Batch {}
+
+impl Batch {
+    fn run(self) {
+        mix_jobs(unsafe { std::slice::from_raw_parts_mut(self.jobs, self.len) }, self.format, self.interpolation);
+    }
+}
+
+struct Worker {
+    // Dropped first to tell the thread to finish
+    jobs: Option<mpsc::Sender<Batch>>,
+    handle: Option<thread::JoinHandle<()>>
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.jobs = None;
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Threads that mix for an engine. Starting threads for every span took longer
+// than the mixing itself, so they stay around and wait for work instead.
+struct Workers {
+    threads: Vec<Worker>,
+    // Each batch reports back here, with false if it panicked
+    done: mpsc::Receiver<bool>,
+    done_tx: mpsc::Sender<bool>
+}
+
+impl Workers {
+    fn new() -> Workers {
+        let (done_tx, done) = mpsc::channel();
+
+        Workers { threads: Vec::new(), done, done_tx }
+    }
+
+    // Makes sure there are exactly n threads.
+    fn start(&mut self, n: usize) {
+        self.threads.truncate(n);
+
+        while self.threads.len() < n {
+            let (tx, rx) = mpsc::channel::<Batch>();
+            let done = self.done_tx.clone();
+
+            let handle = thread::spawn(move || {
+                for batch in rx {
+                    let ok = panic::catch_unwind(panic::AssertUnwindSafe(|| batch.run())).is_ok();
+
+                    let _ = done.send(ok);
+                }
+            });
+
+            self.threads.push(Worker { jobs: Some(tx), handle: Some(handle) });
+        }
+    }
+}
+
+// Threads belong to the engine they were started for, so a copy starts its own.
+impl Clone for Workers {
+    fn clone(&self) -> Workers {
+        Workers::new()
+    }
+}
+
+// Which track a buffer belongs to, see PlaybackEngine::new for the layout.
+fn buffer_track(buf: usize) -> usize {
+    if buf >= 128 {
+        buf - 120
+    } else {
+        buf % 8
+    }
+}
+
+fn truncated_frames_per_tick(sample_rate: u32, wait: u16) -> usize {
+    ((sample_rate as f32 / 1000.0) * wait as f32) as usize
+}
+
+// TODO: Create a MixingBuffer or something...
+// One stereo frame on the mixing bus, scaled so 16-bit full scale is 32768.
+pub type Frame = [f32; 2];
+
+fn mix(dst: &mut [Frame], dst_fmt: WavFormat, srcs: &mut [RenderBuffer], interpolation: Interpolation, mixing: Mixing) {
+    let freq = dst_fmt.sample_rate as f64;
+
+    for buf in srcs.iter_mut() {
+        if !buf.playing {
+            continue;
+        }
+
+        // Nothing changes these until the next tick
+        let advance = buf.frequency as f64 / freq;
+
+        let vol = centibel_to_scale(buf.volume);
+
+        let (pan_l, pan_r) =
+            match buf.pan.signum() {
+                 0 => (1.0, 1.0),
+                 1 => (centibel_to_scale(-buf.pan), 1.0),
+                -1 => (1.0, centibel_to_scale(buf.pan)),
+                 _ => unsafe { std::hint::unreachable_unchecked() }
+            };
+
+        let gains = Gains { advance, vol, pan_l, pan_r };
+
+        // Picking the interpolation out here gives every mode its own tight loop.
+        match interpolation {
+            Interpolation::Nearest  => mix_buffer(dst, buf, gains, mixing, |buf, pos, _| sample(buf, pos)),
+            Interpolation::Linear   => mix_buffer(dst, buf, gains, mixing, |buf, pos, r1| {
+                let (s1, s2) = (sample(buf, pos), after(buf, pos, 1));
+
+                s1 + (s2 - s1) * r1
+            }),
+            Interpolation::Cosine   => mix_buffer(dst, buf, gains, mixing, |buf, pos, r1| {
+                let (s1, s2) = (sample(buf, pos), after(buf, pos, 1));
+                let r2 = (1.0 - f32::cos(r1 * PI)) / 2.0;
+
+                s1 * (1.0 - r2) + s2 * r2
+            }),
+            Interpolation::Cubic    => mix_buffer(dst, buf, gains, mixing, |buf, pos, r1| {
+                cubic_interp(sample(buf, pos), after(buf, pos, 1), before(buf, pos, 1), after(buf, pos, 2), r1)
+            }),
+            Interpolation::Lanczos4 => mix_buffer(dst, buf, gains, mixing, |buf, pos, r1| {
+                lanczos_interp(sample(buf, pos), after(buf, pos, 1), after(buf, pos, 2), before(buf, pos, 1), r1)
+            }),
+            // Ideally we want sinc/lanczos interpolation, since that's what DirectSound appears to use.
+            Interpolation::Lanczos6 => mix_buffer(dst, buf, gains, mixing, |buf, pos, r1| {
+                lanczos_interp6(sample(buf, pos), after(buf, pos, 1), after(buf, pos, 2),
+                                before(buf, pos, 1), before(buf, pos, 2), after(buf, pos, 3), r1)
+            })
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Gains {
+    // index into sound samples
+    advance: f64,
+    vol: f32,
+    pan_l: f32,
+    pan_r: f32
+}
+
+#[inline(always)]
+fn mix_buffer<I: Fn(&RenderBuffer, usize, f32) -> f32>(dst: &mut [Frame], buf: &mut RenderBuffer, gains: Gains, mixing: Mixing, interp: I) {
+    let Gains { advance, vol, pan_l, pan_r } = gains;
+
+    for frame in dst.iter_mut() {
+        let pos = buf.position as usize + buf.base_pos;
+        let r1 = buf.position.fract() as f32;
+
+        // -1..1
+        let s = interp(buf, pos, r1);
+
+        // -128..128
+        let sl = s * pan_l * vol * 32768.0;
+        let sr = s * pan_r * vol * 32768.0;
+
+        if !buf.step(advance) {
+            break;
+        }
+
+        add(frame, [sl, sr], mixing);
+    }
+}
+
+#[inline(always)]
+fn add(frame: &mut Frame, src: Frame, mixing: Mixing) {
+    match mixing {
+        Mixing::Float => {
+            frame[0] += src[0];
+            frame[1] += src[1];
+        }
+        Mixing::Saturating => {
+            frame[0] = (frame[0] as i16).saturating_add(src[0] as i16) as f32;
+            frame[1] = (frame[1] as i16).saturating_add(src[1] as i16) as f32;
+        }
+    }
+}
+
+fn add_frames(dst: &mut [Frame], src: &[Frame], mixing: Mixing) {
+    for (frame, &src) in dst.iter_mut().zip(src) {
+        add(frame, src, mixing);
+    }
+}
+
+// The sample at pos, as -1..1
+#[inline(always)]
+fn sample(buf: &RenderBuffer, pos: usize) -> f32 {
+    (buf.sample.data[pos] as f32 - 128.0) / 128.0
+}
+
+// Samples after pos stop at the end of the current octave's waveform...
+#[inline(always)]
+fn after(buf: &RenderBuffer, pos: usize, n: usize) -> f32 {
+    sample(buf, (pos + n).min(buf.base_pos + buf.len - 1))
+}
+
+// ...but the ones before it don't, and can come from the octave before.
+#[inline(always)]
+fn before(buf: &RenderBuffer, pos: usize, n: usize) -> f32 {
+    sample(buf, pos.saturating_sub(n))
+}
+
+use std::f32::consts::PI;
+
+// s1: sample 1
+// s2: sample 2
+// sp: previous sample (before s1)
+// sn: next sample (after s2)
+// mu: position to interpolate for
+fn cubic_interp(s1: f32, s2: f32, sp: f32, sn: f32, mu: f32) -> f32 {
+    let mu2 = mu * mu;
+    let a0 = sn - s2 - sp + s1;
+    let a1 = sp - s1 - a0;
+    let a2 = s2 - sp;
+    let a3 = s1;
+
+    a0*mu*mu2 + a1*mu2 + a2*mu + a3
+}
+
+fn sinc(x: f32) -> f32
+{
+    if x.abs() <= f32::EPSILON
+    {
+        return 1.0;
+    }
+
+    let y = x * PI;
+
+    y.sin() / y
+}
+
+fn lanczos(x: f32, a: f32) -> f32
+{
+    if x.abs() >= a
+    {
+        return 0.0;
+    }
+
+    sinc(x) * sinc(x / a)
+}
+
+fn lanczos_interp(s1: f32, s2: f32, s3: f32, s4: f32, r: f32) -> f32
+{
+    // assuming floor(x) = 0
+    (s4 * lanczos(r - -1.0, 2.0)) +
+    (s1 * lanczos(r, 2.0)) +
+    (s2 * lanczos(r - 1.0, 2.0)) +
+    (s3 * lanczos(r - 2.0, 2.0))
+}
+
+fn lanczos_interp6(s1: f32, s2: f32, s3: f32, s4: f32, s5: f32, s6: f32, r: f32) -> f32
+{
+    // assuming floor(x) = 0
+    (s5 * lanczos(r - -2.0, 3.0)) +
+    (s4 * lanczos(r - -1.0, 3.0)) +
+    (s1 * lanczos(r, 3.0)) +
+    (s2 * lanczos(r - 1.0, 3.0)) +
+    (s3 * lanczos(r - 2.0, 3.0)) +
+    (s6 * lanczos(r - 3.0, 3.0))
+}
+
+pub(crate) fn centibel_to_scale(a: i32) -> f32 {
     f32::powf(10.0, a as f32 / 2000.0)
 }
 
 #[derive(Clone)]
-pub struct RenderBuffer {
+pub(crate) struct RenderBuffer {
     pub position: f64,
     pub frequency: u32,
     pub volume: i32,
@@ -542,18 +1418,95 @@ impl RenderBuffer {
 
     #[inline]
     pub fn set_volume(&mut self, volume: i32) {
-        assert!(volume >= -10000 && volume <= 0);
+        assert!((-10000..=0).contains(&volume));
 
         self.volume = volume;
     }
 
     #[inline]
     pub fn set_pan(&mut self, pan: i32) {
-        assert!(pan >= -10000 && pan <= 10000);
+        assert!((-10000..=10000).contains(&pan));
 
         self.pan = pan;
     }
 
+    // Moves along by `advance` samples. Returns false once the buffer stops playing.
+    #[inline]
+    pub fn step(&mut self, advance: f64) -> bool {
+        self.position += advance;
+
+        if self.position as usize >= self.len {
+            if self.looping && self.nloops != 1 {
+                self.position %= self.len as f64;
+                if self.nloops != -1 {
+                    self.nloops -= 1;
+                }
+            } else {
+                self.position = 0.0;
+                self.playing = false;
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // Back to how RenderBuffer::new left it.
+    pub fn reset(&mut self) {
+        self.position = 0.0;
+        self.frequency = self.sample.format.sample_rate;
+        self.volume = 0;
+        self.pan = 0;
+        self.len = self.sample.data.len();
+        self.playing = false;
+        self.looping = false;
+        self.base_pos = 0;
+        self.nloops = -1;
+    }
+
+    // Plays through `frames` output frames without mixing anything.
+    pub fn skip(&mut self, frames: usize, sample_rate: u32) {
+        if !self.playing {
+            return;
+        }
+
+        let advance = self.frequency as f64 / sample_rate as f64;
+        let len = self.len as f64;
+
+        // Works out where stepping frame by frame would end up. The position can be
+        // off in the last few bits, since rounding errors don't add up the same way.
+        let end = self.position + advance * frames as f64;
+
+        if end < len {
+            self.position = end;
+        } else if !self.looping || self.nloops == 1 {
+            self.position = 0.0;
+            self.playing = false;
+        } else if self.nloops == -1 {
+            self.position = end % len;
+        } else if advance >= len {
+            // Every step wraps around and uses up a loop, so this stops within nloops steps
+            for _ in 0..frames {
+                if !self.step(advance) {
+                    break;
+                }
+            }
+        } else {
+            // Steps are shorter than the buffer, so each time round uses up one loop,
+            // and the buffer stops when it reaches the end with one loop left.
+            let wraps = (end / len) as u64;
+
+            if wraps < self.nloops as u64 {
+                self.position = end % len;
+                self.nloops -= wraps as i32;
+            } else {
+                self.nloops = 1;
+                self.position = 0.0;
+                self.playing = false;
+            }
+        }
+    }
+
     #[inline]
     #[allow(unused)]
     pub fn set_position(&mut self, position: u32) {
@@ -562,3 +1515,127 @@ impl RenderBuffer {
         self.position = position as f64;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::org::test_song;
+
+    fn engine(name: &str) -> PlaybackEngine {
+        PlaybackEngine::new(test_song(name), &SoundBank::builtin().unwrap()).unwrap()
+    }
+
+    // FNV-1a, so renders can be checked against ones from older versions without keeping them around
+    fn fnv1a(bytes: &[u8]) -> u64 {
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &x| (hash ^ x as u64).wrapping_mul(0x100_0000_01b3))
+    }
+
+    #[test]
+    fn too_long_to_count() {
+        let mut playback = engine("Access");
+        playback.loops = Some(0);
+
+        let once = playback.get_total_samples().unwrap();
+
+        for &timing in [TickTiming::Exact, TickTiming::Truncated, TickTiming::Timer(TimerModel::default())].iter() {
+            playback.timing = timing;
+
+            playback.loops = Some(usize::MAX);
+            assert_eq!(playback.get_total_samples(), None);
+
+            playback.loops = Some((u32::MAX / once) as usize + 1);
+            assert_eq!(playback.get_total_samples(), None);
+
+            playback.loops = Some(0);
+            playback.extra = u32::MAX;
+            assert_eq!(playback.get_total_samples(), None);
+
+            playback.extra = 0;
+        }
+    }
+
+    #[test]
+    fn threads_give_the_same_output() {
+        for &mixing in [Mixing::Float, Mixing::Saturating].iter() {
+            let mut one = engine("Gravity");
+            one.mixing = mixing;
+
+            let mut four = one.clone();
+            four.threads = 4;
+
+            // Odd sizes, so spans end in the middle of ticks
+            let mut a = vec![[0.0; 2]; 3001];
+            let mut b = a.clone();
+
+            for _ in 0..20 {
+                one.render_f32(&mut a);
+                four.render_f32(&mut b);
+
+                assert!(a == b, "{:?}", mixing);
+            }
+        }
+    }
+
+    // The first 3 seconds of what 0.5.0 rendered, before mixing and timing could be changed
+    #[test]
+    fn saturating_truncated_matches_older_versions() {
+        for &(name, hash) in [("Access", 0xe576_fa81_4cf2_f489), ("Gravity", 0xed5d_820c_2b99_a3f5)].iter() {
+            let mut playback = engine(name);
+            playback.mixing = Mixing::Saturating;
+            playback.timing = TickTiming::Truncated;
+
+            let mut buf = vec![0; 3 * 44100 * 4];
+
+            assert_eq!(playback.render_bytes(&mut buf), 3 * 44100);
+            assert_eq!(fnv1a(&buf), hash, "{}", name);
+        }
+    }
+
+    fn buffer(len: usize, looping: bool, nloops: i32, frequency: u32) -> RenderBuffer {
+        let format = WavFormat { channels: 1, sample_rate: 22050, bit_depth: 8 };
+        let mut buf = RenderBuffer::new(WavSample { format, data: vec![128; len] });
+
+        buf.playing = true;
+        buf.looping = looping;
+        buf.nloops = nloops;
+        buf.frequency = frequency;
+        buf.position = 3.25;
+
+        buf
+    }
+
+    #[test]
+    fn skip_matches_stepping() {
+        let cases = [
+            (1000, false, -1, 22050),
+            (8, true, -1, 30000),
+            (8, true, 5, 30000),
+            (8, true, 5, 1000),
+            (8, true, 1, 1000),
+            (8, true, 3, 800_000),
+            (8, true, -1, 800_000)
+        ];
+
+        for &(len, looping, nloops, frequency) in cases.iter() {
+            for &frames in [0, 1, 7, 100, 1000, 5000].iter() {
+                let mut skipped = buffer(len, looping, nloops, frequency);
+                let mut stepped = skipped.clone();
+                let advance = frequency as f64 / 44100.0;
+
+                skipped.skip(frames, 44100);
+
+                for _ in 0..frames {
+                    if !stepped.step(advance) {
+                        break;
+                    }
+                }
+
+                let case = (len, looping, nloops, frequency, frames);
+
+                assert_eq!(skipped.playing, stepped.playing, "{:?}", case);
+                assert_eq!(skipped.nloops, stepped.nloops, "{:?}", case);
+                assert!((skipped.position - stepped.position).abs() < 1e-6, "{:?}", case);
+            }
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::playback::{Fade, Frame, PlaybackEngine};
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Something for the audio thread to do before the next block
+enum Command {
+    Play,
+    Pause,
+    Stop,
+    // Carry on with this engine instead, which has already been seeked elsewhere
+    Replace(Box<PlaybackEngine>),
+    Volume(f32),
+    Tempo(u16),
+    Mute(usize, bool),
+    FadeOut(Fade)
+}
+
+// The half that lives on the audio thread. Call render from the audio callback;
+// commands sent through the Controller are picked up at the start of every call,
+// without ever waiting on the other side.
+pub struct Player {
+    engine: PlaybackEngine,
+    commands: Receiver<Command>,
+    // Engines that were replaced go back this way, to be freed off the audio thread
+    retired: Sender<Box<PlaybackEngine>>,
+    playing: bool,
+    volume: f32,
+    // Where the last block's volume ended up, so changes ramp instead of clicking
+    last_volume: f32
+}
+
+// The half that stays with the game or UI. Clone it to control from several threads.
+#[derive(Clone)]
+pub struct Controller {
+    commands: Sender<Command>,
+    shared: Arc<Mutex<Shared>>
+}
+
+// What all the controllers for a player have in common
+struct Shared {
+    // The engine as it was at the start, with the same tempo and mutes as the
+    // player's. Seeks start from a copy of it, so they never hold up the audio thread.
+    start: PlaybackEngine,
+    retired: Receiver<Box<PlaybackEngine>>
+}
+
+impl Player {
+    // Starts out paused. Mixes on the audio thread alone, whatever engine.threads
+    // was, since handing work to other threads would mean waiting for them.
+    pub fn new(mut engine: PlaybackEngine) -> (Player, Controller) {
+        let (tx, rx) = mpsc::channel();
+        let (retired_tx, retired) = mpsc::channel();
+
+        engine.threads = 1;
+
+        let mut start = engine.clone();
+        start.seek(0);
+
+        let player = Player {
+            engine,
+            commands: rx,
+            retired: retired_tx,
+            playing: false,
+            volume: 1.0,
+            last_volume: 1.0
+        };
+
+        let shared = Shared { start, retired };
+
+        (player, Controller { commands: tx, shared: Arc::new(Mutex::new(shared)) })
+    }
+
+    pub fn engine(&self) -> &PlaybackEngine {
+        &self.engine
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing && !self.engine.is_finished()
+    }
+
+    // Fills all of buf with 1.0 as full scale, padding with silence when paused or
+    // once the song is over.
+    pub fn render(&mut self, buf: &mut [Frame]) {
+        self.apply_commands();
+
+        let frames = if self.playing { self.engine.render_f32(buf) } else { 0 };
+
+        buf[frames..].fill([0.0; 2]);
+
+        let (from, to) = (self.last_volume, self.volume);
+
+        if from != 1.0 || to != 1.0 {
+            let step = (to - from) / buf.len().max(1) as f32;
+
+            for (i, frame) in buf.iter_mut().enumerate() {
+                let volume = from + step * i as f32;
+
+                frame[0] *= volume;
+                frame[1] *= volume;
+            }
+        }
+
+        self.last_volume = to;
+    }
+
+    fn apply_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                Command::Play                => self.playing = true,
+                Command::Pause               => self.playing = false,
+                Command::Stop                => {
+                    self.playing = false;
+                    self.engine.seek(0);
+                }
+                Command::Replace(mut engine) => {
+                    std::mem::swap(&mut self.engine, &mut engine);
+
+                    // Only fails once every controller is gone, and then there's nowhere better
+                    let _ = self.retired.send(engine);
+                }
+                Command::Volume(volume)      => self.volume = volume.max(0.0),
+                Command::Tempo(wait)         => self.engine.set_tempo(wait.max(1)),
+                Command::Mute(track, muted)  => self.engine.set_track_muted(track, muted),
+                Command::FadeOut(fade)       => self.engine.fade_out(fade)
+            }
+        }
+    }
+}
+
+// Each of these takes effect at the start of the player's next block.
+// Once the player is gone they do nothing.
+impl Controller {
+    fn send(&self, command: Command) {
+        let _ = self.commands.send(command);
+    }
+
+    // Held while sending anything that changes the start engine, so every controller's
+    // commands reach the player in the same order they changed it in.
+    // Engines the player has finished with get freed here too.
+    fn shared(&self) -> MutexGuard<'_, Shared> {
+        let shared = self.shared.lock().unwrap_or_else(|e| e.into_inner());
+
+        while shared.retired.try_recv().is_ok() {}
+
+        shared
+    }
+
+    // Seeks a copy of the engine on this thread, then hands it over.
+    // Seeking replays the song up to there, which takes a moment for long songs.
+    fn seek_with(&self, seek: impl FnOnce(&mut PlaybackEngine)) {
+        let shared = self.shared();
+        let mut engine = Box::new(shared.start.clone());
+
+        seek(&mut engine);
+
+        self.send(Command::Replace(engine));
+    }
+
+    pub fn play(&self) {
+        self.send(Command::Play);
+    }
+
+    // Keeps the position, so play carries on from here.
+    pub fn pause(&self) {
+        self.send(Command::Pause);
+    }
+
+    // Pauses and goes back to the start.
+    pub fn stop(&self) {
+        self.send(Command::Stop);
+    }
+
+    // See PlaybackEngine::seek. Any fade out in progress is dropped.
+    pub fn seek(&self, tick: u64) {
+        self.seek_with(|engine| engine.seek(tick));
+    }
+
+    pub fn seek_seconds(&self, seconds: f64) {
+        self.seek_with(|engine| engine.seek_seconds(seconds));
+    }
+
+    // 1.0 leaves the song as it is.
+    pub fn set_volume(&self, volume: f32) {
+        self.send(Command::Volume(volume));
+    }
+
+    // Milliseconds per tick, like the song's own wait value.
+    pub fn set_tempo(&self, wait: u16) {
+        let mut shared = self.shared();
+
+        shared.start.set_tempo(wait.max(1));
+        self.send(Command::Tempo(wait));
+    }
+
+    pub fn set_track_muted(&self, track: usize, muted: bool) {
+        assert!(track < 16);
+
+        let mut shared = self.shared();
+
+        shared.start.set_track_muted(track, muted);
+        self.send(Command::Mute(track, muted));
+    }
+
+    pub fn fade_out(&self, fade: Fade) {
+        self.send(Command::FadeOut(fade));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bnk::SoundBank;
+    use crate::org::test_song;
+
+    fn engine() -> PlaybackEngine {
+        PlaybackEngine::new(test_song("Access"), &SoundBank::builtin().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn seek_keeps_mutes_and_tempo() {
+        let (mut player, controller) = Player::new(engine());
+        let mut buf = vec![[0.0; 2]; 1000];
+
+        controller.play();
+        player.render(&mut buf);
+
+        controller.set_track_muted(2, true);
+        controller.set_tempo(90);
+        controller.seek_seconds(5.0);
+        player.render(&mut buf);
+
+        let mut expected = engine();
+        let mut frames = vec![[0.0; 2]; 1000];
+
+        expected.set_track_muted(2, true);
+        expected.set_tempo(90);
+        expected.seek_seconds(5.0);
+        expected.render_f32(&mut frames);
+
+        assert!(player.engine().is_track_muted(2));
+        assert_eq!(player.engine().get_tempo(), 90);
+        assert!(buf == frames);
+    }
+
+    #[test]
+    fn replaced_engines_are_freed_elsewhere() {
+        let mut engine = engine();
+        engine.threads = 4;
+
+        let (mut player, controller) = Player::new(engine);
+        let mut buf = vec![[0.0; 2]; 1000];
+
+        assert_eq!(player.engine().threads, 1);
+
+        controller.seek_seconds(1.0);
+        player.render(&mut buf);
+
+        let retired = controller.shared.lock().unwrap().retired.try_recv();
+        assert!(retired.is_ok_and(|x| x.get_position() == 0));
+
+        // The next seek frees anything that came back in the meantime
+        controller.seek_seconds(2.0);
+        player.render(&mut buf);
+        controller.seek_seconds(3.0);
+
+        assert!(controller.shared.lock().unwrap().retired.try_recv().is_err());
+    }
+}
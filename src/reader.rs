@@ -0,0 +1,98 @@
+use crate::error::{Error, Result};
+
+use byteorder::{ByteOrder, LE};
+use std::io;
+
+// Keeps track of how far into the file we are, so errors can point at the offending byte.
+pub struct Reader<R> {
+    inner: R,
+    offset: u64
+}
+
+impl<R: io::Read> Reader<R> {
+    pub fn new(inner: R) -> Reader<R> {
+        Reader { inner, offset: 0 }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn bytes(&mut self, buf: &mut [u8]) -> Result<()> {
+        let offset = self.offset;
+        let mut done = 0;
+
+        while done < buf.len() {
+            match self.inner.read(&mut buf[done..]) {
+                Ok(0) => return Err(Error::Truncated { offset, expected: buf.len() }),
+                Ok(n) => {
+                    done += n;
+                    self.offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into())
+            }
+        }
+
+        Ok(())
+    }
+
+    // Like bytes, but for lengths we can't trust. Memory is only committed
+    // as data actually arrives, so a bogus length fails early instead of
+    // allocating the whole thing up front.
+    pub fn vec(&mut self, len: usize) -> Result<Vec<u8>> {
+        const CHUNK: usize = 4096;
+
+        let offset = self.offset;
+        let mut buf = Vec::new();
+        let mut chunk = [0; CHUNK];
+
+        while buf.len() < len {
+            let want = (len - buf.len()).min(CHUNK);
+
+            match self.inner.read(&mut chunk[..want]) {
+                Ok(0) => return Err(Error::Truncated { offset, expected: len }),
+                Ok(n) => {
+                    buf.extend_from_slice(&chunk[..n]);
+                    self.offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into())
+            }
+        }
+
+        Ok(buf)
+    }
+
+    pub fn rest(&mut self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+
+        self.offset += io::Read::read_to_end(&mut self.inner, &mut buf)? as u64;
+
+        Ok(buf)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        let mut buf = [0; 1];
+        self.bytes(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let mut buf = [0; 2];
+        self.bytes(&mut buf)?;
+        Ok(LE::read_u16(&buf))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let mut buf = [0; 4];
+        self.bytes(&mut buf)?;
+        Ok(LE::read_u32(&buf))
+    }
+
+    pub fn i32(&mut self) -> Result<i32> {
+        let mut buf = [0; 4];
+        self.bytes(&mut buf)?;
+        Ok(LE::read_i32(&buf))
+    }
+}
@@ -0,0 +1,254 @@
+use crate::bnk::SoundBank;
+use crate::org::{Song, Version};
+
+use std::fmt;
+
+// Highest key Org Maker lets you place (8 octaves of 12 keys).
+pub const MAX_KEY: u8 = 95;
+// PAN_TBL has 13 entries.
+pub const MAX_PAN: u8 = 12;
+// Dummy value for "don't change this" in key, vol and pan.
+pub const DUMMY: u8 = 255;
+// Range of the fine tuning knob in Org Maker.
+pub const FREQ_RANGE: std::ops::RangeInclusive<u16> = 100..=1900;
+// Highest sample rate the renderer goes up to. One pass up to the loop end
+// has to fit in a u32 count of frames at this rate.
+pub const MAX_SAMPLE_RATE: u64 = 192000;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub enum Severity {
+    // Plays, but probably not the way the author intended.
+    Warning,
+    // Would crash or corrupt playback.
+    Error
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error   => write!(f, "error")
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Field {
+    Wait,
+    Beats,
+    Steps,
+    LoopStart,
+    LoopEnd,
+    Frequency,
+    Instrument,
+    Pipi,
+    Position,
+    Key,
+    Length,
+    // Every u8 volume maps into DirectSound's range, with 255 as the dummy value,
+    // so this only shows up for events that can't have any effect at all.
+    Volume,
+    Pan,
+    Trailing
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Field::Wait       => "wait",
+            Field::Beats      => "beats",
+            Field::Steps      => "steps",
+            Field::LoopStart  => "loop start",
+            Field::LoopEnd    => "loop end",
+            Field::Frequency  => "frequency",
+            Field::Instrument => "instrument",
+            Field::Pipi       => "pi flag",
+            Field::Position   => "position",
+            Field::Key        => "key",
+            Field::Length     => "length",
+            Field::Volume     => "volume",
+            Field::Pan        => "pan",
+            Field::Trailing   => "trailing bytes"
+        };
+
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    // None for song-wide problems
+    pub track: Option<usize>,
+    // Index into the track's note list
+    pub note: Option<usize>,
+    pub field: Field,
+    pub value: i64,
+    pub problem: &'static str
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.severity)?;
+
+        if let Some(track) = self.track {
+            write!(f, "track {}, ", track)?;
+        }
+
+        if let Some(note) = self.note {
+            write!(f, "note {}, ", note)?;
+        }
+
+        write!(f, "{} {}: {}", self.field, self.value, self.problem)
+    }
+}
+
+struct Issues(Vec<Issue>);
+
+impl Issues {
+    fn song(&mut self, severity: Severity, field: Field, value: i64, problem: &'static str) {
+        self.0.push(Issue { severity, track: None, note: None, field, value, problem });
+    }
+
+    fn track(&mut self, severity: Severity, track: usize, field: Field, value: i64, problem: &'static str) {
+        self.0.push(Issue { severity, track: Some(track), note: None, field, value, problem });
+    }
+
+    fn note(&mut self, severity: Severity, track: usize, note: usize, field: Field, value: i64, problem: &'static str) {
+        self.0.push(Issue { severity, track: Some(track), note: Some(note), field, value, problem });
+    }
+}
+
+impl Song {
+    /// Checks the song for anything that would make playback misbehave.
+    ///
+    /// Org Maker and Cave Story only check the magic number, so this is
+    /// stricter than the original. Issues are returned in file order.
+    pub fn validate(&self, bank: &SoundBank) -> Vec<Issue> {
+        use Severity::*;
+
+        let mut issues = Issues(Vec::new());
+
+        let time = &self.time;
+        let (start, end) = (time.loop_range.start, time.loop_range.end);
+
+        if time.wait == 0 {
+            issues.song(Error, Field::Wait, 0, "tempo must be at least 1ms per tick");
+        }
+
+        if self.display.beats == 0 {
+            issues.song(Warning, Field::Beats, 0, "bars need at least one beat");
+        }
+
+        if self.display.steps == 0 {
+            issues.song(Warning, Field::Steps, 0, "beats need at least one step");
+        }
+
+        if start < 0 {
+            issues.song(Error, Field::LoopStart, start as i64, "loop starts before the song does");
+        }
+
+        if end <= start {
+            issues.song(Error, Field::LoopEnd, end as i64, "loop must end after it starts");
+        } else if end as u64 * time.wait as u64 * MAX_SAMPLE_RATE / 1000 > u32::MAX as u64 {
+            issues.song(Error, Field::LoopEnd, end as i64, "song is too long to render");
+        }
+
+        let last_note = self.tracks.iter().flat_map(|x| x.notes.iter()).map(|x| x.pos).max();
+
+        if let Some(last) = last_note.filter(|&x| end > x.saturating_add(self.display.ticks_per_bar())) {
+            issues.song(Warning, Field::LoopEnd, end as i64,
+                if last < start { "loop starts after the last note" } else { "loop goes on for more than a bar after the last note" });
+        }
+
+        for (i, track) in self.tracks.iter().enumerate() {
+            let inst = &track.inst;
+            let drum = i >= 8;
+
+            if !drum && inst.inst as usize >= bank.wave100.len() / 256 {
+                issues.track(Error, i, Field::Instrument, inst.inst as i64, "no such waveform in WAVE100");
+            }
+
+            if drum && inst.inst as usize >= bank.samples.len() {
+                issues.track(Error, i, Field::Instrument, inst.inst as i64, "no such drum sample in the sound bank");
+            }
+
+            if !FREQ_RANGE.contains(&inst.freq) {
+                issues.track(Warning, i, Field::Frequency, inst.freq as i64, "outside of Org Maker's tuning range");
+            }
+
+            if inst.pipi > 1 {
+                issues.track(Warning, i, Field::Pipi, inst.pipi as i64, "should be 0 or 1");
+            } else if inst.pipi != 0 && matches!(self.version, Version::Beta) {
+                issues.track(Warning, i, Field::Pipi, inst.pipi as i64, "Org-01 has no pi flag");
+            }
+
+            let mut last_pos = None;
+
+            for (j, note) in track.notes.iter().enumerate() {
+                if note.pos < 0 {
+                    issues.note(Warning, i, j, Field::Position, note.pos as i64, "note is before the start of the song and never plays");
+                } else if note.pos >= end {
+                    issues.note(Warning, i, j, Field::Position, note.pos as i64, "note is past the loop end and never plays");
+                }
+
+                match last_pos {
+                    Some(last) if note.pos == last =>
+                        issues.note(Warning, i, j, Field::Position, note.pos as i64, "duplicate position, only the first note plays"),
+                    Some(last) if note.pos < last =>
+                        issues.note(Warning, i, j, Field::Position, note.pos as i64, "notes are out of order"),
+                    _ => {}
+                }
+
+                last_pos = Some(note.pos);
+
+                if note.key > MAX_KEY && note.key != DUMMY {
+                    issues.note(Error, i, j, Field::Key, note.key as i64, "key is out of range");
+                }
+
+                if note.pan > MAX_PAN && note.pan != DUMMY {
+                    issues.note(Error, i, j, Field::Pan, note.pan as i64, "pan is out of range");
+                }
+
+                if note.key == DUMMY && note.vol == DUMMY && note.pan == DUMMY {
+                    issues.note(Warning, i, j, Field::Volume, note.vol as i64, "event changes nothing");
+                }
+
+                // Drums always play the whole sample.
+                if !drum && note.key != DUMMY && note.len == 0 {
+                    issues.note(Warning, i, j, Field::Length, 0, "note is cut off as soon as it starts");
+                }
+            }
+        }
+
+        if !self.trailing.is_empty() {
+            issues.song(Warning, Field::Trailing, self.trailing.len() as i64, "unexpected data after the last track");
+        }
+
+        issues.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::org::test_song;
+
+    fn loop_end_issues(song: &Song) -> Vec<Severity> {
+        let bank = SoundBank::builtin().unwrap();
+
+        song.validate(&bank).into_iter().filter(|x| x.field == Field::LoopEnd).map(|x| x.severity).collect()
+    }
+
+    #[test]
+    fn loop_end() {
+        let mut song = test_song("Access");
+        assert_eq!(loop_end_issues(&song), []);
+
+        song.time.loop_range.end += song.display.ticks_per_bar() * 2;
+        assert_eq!(loop_end_issues(&song), [Severity::Warning]);
+
+        song.time.loop_range.end = 2_000_000_000;
+        assert_eq!(loop_end_issues(&song), [Severity::Error, Severity::Warning]);
+    }
+}
@@ -53,58 +53,91 @@ impl fmt::Display for WavSample {
     }
 }
 
-use byteorder::{LE, ReadBytesExt};
+use crate::error::{Error, Result};
+use crate::reader::Reader;
 use std::io;
 
 impl RiffChunk {
-    pub fn read_from<R: io::Read>(mut f: R) -> io::Result<RiffChunk> {
+    pub fn read_from<R: io::Read>(f: R) -> Result<RiffChunk> {
+        RiffChunk::read(&mut Reader::new(f))
+    }
+
+    pub(crate) fn read<R: io::Read>(f: &mut Reader<R>) -> Result<RiffChunk> {
         let mut id = [0; 4];
         
-        f.read_exact(&mut id)?;
-        let length = f.read_u32::<LE>()?;
+        f.bytes(&mut id)?;
+        let length = f.u32()?;
         
         Ok(RiffChunk { id, length })
     }
+
+    // Reads the next chunk header, which must be `id`.
+    fn expect<R: io::Read>(f: &mut Reader<R>, id: &'static str) -> Result<RiffChunk> {
+        let offset = f.offset();
+        let chunk = RiffChunk::read(f)?;
+
+        if chunk.id != id.as_bytes() {
+            return Err(Error::BadMagic { offset, expected: id, found: chunk.id.to_vec() });
+        }
+
+        Ok(chunk)
+    }
 }
 
 impl WavSample {
-    pub fn read_from<R: io::Read>(mut f: R) -> io::Result<WavSample> {
-        let riff = RiffChunk::read_from(&mut f)?;
+    pub fn read_from<R: io::Read>(f: R) -> Result<WavSample> {
+        WavSample::read(&mut Reader::new(f))
+    }
+
+    pub(crate) fn read<R: io::Read>(f: &mut Reader<R>) -> Result<WavSample> {
+        let offset = f.offset();
+        let riff = RiffChunk::read(f)?;
         
         match &riff.id {
             b"RIFF" => {},
-            b"RIFX" => panic!("Cannot handle RIFX data!"),
-            _       => panic!("Expected RIFF signature, found {}", riff)
+            b"RIFX" => return Err(Error::UnsupportedWaveFormat { offset, reason: "big endian (RIFX) data" }),
+            _       => return Err(Error::BadMagic { offset, expected: "RIFF", found: riff.id.to_vec() })
         }
         
+        let offset = f.offset();
         let mut rfmt = [0; 4];
         
-        f.read_exact(&mut rfmt)?;
+        f.bytes(&mut rfmt)?;
         
-        assert_eq!(rfmt, *b"WAVE");
-        
-        let fmt = RiffChunk::read_from(&mut f)?;
+        if rfmt != *b"WAVE" {
+            return Err(Error::BadMagic { offset, expected: "WAVE", found: rfmt.to_vec() });
+        }
         
-        assert_eq!(fmt.id, *b"fmt ");
+        let _fmt = RiffChunk::expect(f, "fmt ")?;
         //assert_eq!(fmt.length, 16);
         
-        let afmt = f.read_u16::<LE>()?;
+        let offset = f.offset();
+        let afmt = f.u16()?;
         
-        debug_assert!(afmt == 1);
+        if afmt != 1 {
+            return Err(Error::UnsupportedWaveFormat { offset, reason: "only PCM data is supported" });
+        }
         
-        let channels = f.read_u16::<LE>()?;
-        let samples  = f.read_u32::<LE>()?;
-        let _brate = f.read_u32::<LE>()?;
-        let _balgn = f.read_u16::<LE>()?;
-        let bits     = f.read_u16::<LE>()?;
+        let channels = f.u16()?;
+        let samples  = f.u32()?;
+        let _brate = f.u32()?;
+        let _balgn = f.u16()?;
+        let bits     = f.u16()?;
         
-        let data = RiffChunk::read_from(&mut f)?;
+        // Playback reads samples as single bytes, one per frame
+        if channels != 1 || bits != 8 {
+            return Err(Error::UnsupportedWaveFormat { offset, reason: "only 8-bit mono samples are supported" });
+        }
         
-        assert_eq!(data.id, *b"data");
+        let offset = f.offset();
+        let data = RiffChunk::expect(f, "data")?;
         
-        let mut buf = vec![0; data.length as usize];
+        if data.length == 0 {
+            return Err(Error::UnsupportedWaveFormat { offset, reason: "sample has no data" });
+        }
         
-        f.read_exact(&mut buf)?;
+        // Grows as the data comes in, so a bogus length can't allocate gigabytes up front
+        let buf = f.vec(data.length as usize)?;
         
         Ok(
             WavSample {
@@ -118,3 +151,280 @@ impl WavSample {
         )
     }
 }
+
+use byteorder::{LE, WriteBytesExt};
+use std::convert::TryFrom;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+// KSDATAFORMAT_SUBTYPE_PCM
+const SUBTYPE_PCM: [u8; 16] = [
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00,
+    0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71
+];
+
+// SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT
+const STEREO_MASK: u32 = 0x3;
+
+#[allow(non_snake_case, clippy::upper_case_acronyms)]
+#[repr(C)]
+struct WAVEFORMATEX {
+    // 1 for PCM, 3 for float, 0xFFFE for extensible
+    wFormatTag: u16,
+    // Must be 2
+    nChannels: u16,
+    // Output sample rate
+    nSamplesPerSec: u32,
+    // Must be nSamplesPerSec * nBlockAlign
+    nAvgBytesPerSec: u32,
+    // Must be nChannels * wBitsPerSample / 8
+    nBlockAlign: u16,
+    // 16, 24 or 32
+    wBitsPerSample: u16,
+}
+
+#[allow(non_snake_case)]
+impl WAVEFORMATEX {
+    const fn new(wFormatTag: u16, nChannels: u16, nSamplesPerSec: u32, wBitsPerSample: u16) -> Self {
+        let nBlockAlign = nChannels * (wBitsPerSample / 8);
+        let nAvgBytesPerSec = nSamplesPerSec * nBlockAlign as u32;
+
+        WAVEFORMATEX {
+            wFormatTag,
+            nChannels,
+            nSamplesPerSec,
+            nAvgBytesPerSec,
+            nBlockAlign,
+            wBitsPerSample
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        // Anything that isn't plain PCM needs cbSize, and extensible needs the extra fields on top.
+        let size = match self.wFormatTag {
+            WAVE_FORMAT_PCM        => 16,
+            WAVE_FORMAT_EXTENSIBLE => 40,
+            _                      => 18
+        };
+
+        let mut out = Vec::with_capacity(8 + size as usize);
+        out.extend_from_slice(b"fmt ");
+        out.write_u32::<LE>(size).unwrap();
+        out.write_u16::<LE>(self.wFormatTag).unwrap();
+        out.write_u16::<LE>(self.nChannels).unwrap();
+        out.write_u32::<LE>(self.nSamplesPerSec).unwrap();
+        out.write_u32::<LE>(self.nAvgBytesPerSec).unwrap();
+        out.write_u16::<LE>(self.nBlockAlign).unwrap();
+        out.write_u16::<LE>(self.wBitsPerSample).unwrap();
+
+        if size > 16 {
+            out.write_u16::<LE>(size as u16 - 18).unwrap();
+        }
+
+        if self.wFormatTag == WAVE_FORMAT_EXTENSIBLE {
+            // wValidBitsPerSample
+            out.write_u16::<LE>(self.wBitsPerSample).unwrap();
+            out.write_u32::<LE>(if self.nChannels == 2 { STEREO_MASK } else { 0 }).unwrap();
+            out.extend_from_slice(&SUBTYPE_PCM);
+        }
+
+        out
+    }
+}
+
+// Which kind of samples we write. Organism doesn't do 32-bit integers,
+// so a WavFormat with 32 bits always means float.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum SampleFormat {
+    #[default]
+    Int16,
+    Int24,
+    Float32
+}
+
+impl SampleFormat {
+    pub fn bit_depth(self) -> u16 {
+        match self {
+            SampleFormat::Int16   => 16,
+            SampleFormat::Int24   => 24,
+            SampleFormat::Float32 => 32
+        }
+    }
+
+    pub fn bytes_per_sample(self) -> usize {
+        self.bit_depth() as usize / 8
+    }
+
+    // Writes one sample, with 1.0 as full scale, to the start of out.
+    pub fn encode(self, x: f32, out: &mut [u8]) {
+        match self {
+            SampleFormat::Int16 =>
+                out[..2].copy_from_slice(&((x * 32768.0) as i16).to_le_bytes()),
+            SampleFormat::Int24 => {
+                let x = ((x * 8388608.0) as i32).clamp(-0x80_0000, 0x7F_FFFF);
+                out[..3].copy_from_slice(&x.to_le_bytes()[..3]);
+            }
+            SampleFormat::Float32 =>
+                out[..4].copy_from_slice(&x.to_le_bytes())
+        }
+    }
+
+    pub fn from_bit_depth(bits: u16) -> Option<SampleFormat> {
+        match bits {
+            16 => Some(SampleFormat::Int16),
+            24 => Some(SampleFormat::Int24),
+            32 => Some(SampleFormat::Float32),
+            _  => None
+        }
+    }
+}
+
+impl std::str::FromStr for SampleFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "s16" => Ok(SampleFormat::Int16),
+            "s24" => Ok(SampleFormat::Int24),
+            "f32" => Ok(SampleFormat::Float32),
+            _     => Err(())
+        }
+    }
+}
+
+impl WavFormat {
+    pub fn block_align(&self) -> u32 {
+        self.channels as u32 * (self.bit_depth / 8) as u32
+    }
+}
+
+fn header_bytes(format: WavFormat, data_size: u32) -> Vec<u8> {
+    let tag = match format.bit_depth {
+        32 => WAVE_FORMAT_IEEE_FLOAT,
+        // Some players refuse 24-bit PCM without the extensible header
+        24 => WAVE_FORMAT_EXTENSIBLE,
+        _  => WAVE_FORMAT_PCM
+    };
+
+    let fmt = WAVEFORMATEX::new(tag, format.channels, format.sample_rate, format.bit_depth).to_bytes();
+
+    let mut out = Vec::with_capacity(64);
+    out.extend_from_slice(b"RIFF");
+    // Filled in below
+    out.write_u32::<LE>(0).unwrap();
+    out.extend_from_slice(b"WAVE");
+    out.extend_from_slice(&fmt);
+
+    // Non-PCM formats need to say how many frames there are
+    if tag == WAVE_FORMAT_IEEE_FLOAT {
+        out.extend_from_slice(b"fact");
+        out.write_u32::<LE>(4).unwrap();
+        out.write_u32::<LE>(data_size / format.block_align()).unwrap();
+    }
+
+    out.extend_from_slice(b"data");
+    out.write_u32::<LE>(data_size).unwrap();
+
+    let riff_size = (out.len() as u32 - 8).saturating_add(data_size);
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    out
+}
+
+// For when the amount of data is known up front, or the output can't seek.
+pub fn write_header<W: io::Write>(mut out: W, format: WavFormat, data_size: u32) -> io::Result<()> {
+    out.write_all(&header_bytes(format, data_size))
+}
+
+// Writes a wav file without knowing its length in advance.
+// The sizes in the header get filled in by finish().
+pub struct WavWriter<W: io::Write + io::Seek> {
+    out: W,
+    format: WavFormat,
+    start: u64,
+    data_size: u64
+}
+
+impl<W: io::Write + io::Seek> WavWriter<W> {
+    pub fn new(mut out: W, format: WavFormat) -> io::Result<WavWriter<W>> {
+        let start = out.stream_position()?;
+
+        write_header(&mut out, format, 0)?;
+
+        Ok(WavWriter { out, format, start, data_size: 0 })
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let header = header_bytes(self.format, 0);
+
+        let data_size = u32::try_from(self.data_size)
+            .ok()
+            .filter(|&x| x <= u32::MAX - header.len() as u32)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "too much data for a wav file"))?;
+
+        let end = self.out.stream_position()?;
+
+        // Same length as before, only the sizes change
+        self.out.seek(io::SeekFrom::Start(self.start))?;
+        write_header(&mut self.out, self.format, data_size)?;
+        self.out.seek(io::SeekFrom::Start(end))?;
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+}
+
+impl<W: io::Write + io::Seek> io::Write for WavWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        self.data_size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn riff(channels: u16, bits: u16, length: u32, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(b"RIFF");
+        out.write_u32::<LE>(36 + data.len() as u32).unwrap();
+        out.extend_from_slice(b"WAVEfmt ");
+        out.write_u32::<LE>(16).unwrap();
+        out.write_u16::<LE>(WAVE_FORMAT_PCM).unwrap();
+        out.write_u16::<LE>(channels).unwrap();
+        out.write_u32::<LE>(22050).unwrap();
+        out.write_u32::<LE>(22050).unwrap();
+        out.write_u16::<LE>(1).unwrap();
+        out.write_u16::<LE>(bits).unwrap();
+        out.extend_from_slice(b"data");
+        out.write_u32::<LE>(length).unwrap();
+        out.extend_from_slice(data);
+        out
+    }
+
+    #[test]
+    fn rejects_unplayable_samples() {
+        let unsupported = |bytes: Vec<u8>| matches!(WavSample::read_from(&bytes[..]), Err(Error::UnsupportedWaveFormat { .. }));
+
+        assert!(WavSample::read_from(&riff(1, 8, 3, &[1, 2, 3])[..]).is_ok());
+        assert!(unsupported(riff(1, 0, 3, &[1, 2, 3])));
+        assert!(unsupported(riff(0, 8, 3, &[1, 2, 3])));
+        assert!(unsupported(riff(2, 16, 4, &[1, 2, 3, 4])));
+        assert!(unsupported(riff(1, 8, 0, &[])));
+    }
+
+    #[test]
+    fn huge_length_is_truncated() {
+        let bytes = riff(1, 8, u32::MAX, &[1, 2, 3]);
+
+        assert!(matches!(WavSample::read_from(&bytes[..]), Err(Error::Truncated { .. })));
+    }
+}